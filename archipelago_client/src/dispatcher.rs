@@ -0,0 +1,355 @@
+use crate::{BoxTransport, Client, ClientSender, Error, Keepalive, Transport};
+use archipelago_protocol::{
+    Bounce, ClientMessage, ClientStatus, DataPackage, DataStorageOperation, Get, GetDataPackage, LocationChecks, LocationInfo, LocationScouts, Retrieved, Say, ServerMessage, Set, SetNotify, SetReply,
+    StatusUpdate,
+};
+use serde_json::Value;
+use std::{
+    collections::{hash_map::RandomState, HashMap, VecDeque},
+    hash::{BuildHasher, Hasher},
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    select, spawn,
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot, Mutex as AsyncMutex,
+    },
+};
+
+/**
+ * The extra `Set` argument that tells the reply to a `Set` apart from the `SetReply`s others cause for the same key,
+ * which the server passes back
+ */
+const SET_TAG: &str = "archipelago_client_tag";
+
+/**
+ * Tag `set` with a random value, so its reply can be found with `set_tag` and the tags of other clients don't collide
+ */
+pub(crate) fn tag_set(set: &mut Set) -> String {
+    let tag = format!("{:016x}", RandomState::new().build_hasher().finish());
+    set.extra.insert(SET_TAG.into(), Value::String(tag.clone()));
+    tag
+}
+
+pub(crate) fn set_tag(reply: &SetReply) -> Option<&str> {
+    reply.extra().get(SET_TAG).and_then(Value::as_str)
+}
+
+/**
+ * Requests waiting for a reply from the server.
+ *
+ * The server answers requests of the same kind in the order they were sent, so replies are matched to the oldest
+ * waiting request of their kind. `SetReply` is matched by the tag of the `Set`, since the server also sends it for
+ * changes others make to keys watched with `SetNotify`, and is passed on to every subscription watching its key.
+ *
+ * Every lost connection starts a new generation. Requests of an older generation were failed already, so they must not
+ * be sent anymore, or their replies would be matched to newer requests
+ */
+#[derive(Debug, Default)]
+pub(crate) struct Pending {
+    retrieved: VecDeque<oneshot::Sender<Retrieved>>,
+    set_reply: HashMap<String, oneshot::Sender<SetReply>>,
+    location_info: VecDeque<oneshot::Sender<LocationInfo>>,
+    data_package: VecDeque<oneshot::Sender<DataPackage>>,
+    watchers: HashMap<String, Vec<UnboundedSender<SetReply>>>,
+    #[cfg(feature = "native")]
+    generation: u64,
+    closed: bool,
+}
+
+impl Pending {
+    #[cfg(feature = "native")]
+    pub(crate) fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /**
+     * Register a waiting request for the reply to `message`, if it has one. A `Set` is tagged for its reply
+     */
+    pub(crate) fn register(&mut self, message: &mut ClientMessage) -> Option<Awaiting> {
+        match message {
+            ClientMessage::Get(_) => {
+                let (sender, receiver) = oneshot::channel();
+                self.retrieved.push_back(sender);
+                Some(Awaiting::Retrieved(receiver))
+            }
+            ClientMessage::Set(set) if set.want_reply => {
+                let (sender, receiver) = oneshot::channel();
+                self.set_reply.insert(tag_set(set), sender);
+                Some(Awaiting::SetReply(receiver))
+            }
+            ClientMessage::LocationScouts(_) => {
+                let (sender, receiver) = oneshot::channel();
+                self.location_info.push_back(sender);
+                Some(Awaiting::LocationInfo(receiver))
+            }
+            ClientMessage::GetDataPackage(_) => {
+                let (sender, receiver) = oneshot::channel();
+                self.data_package.push_back(sender);
+                Some(Awaiting::DataPackage(receiver))
+            }
+            _ => None,
+        }
+    }
+
+    /**
+     * Start watching `keys`, the `SetNotify` for them still has to be sent
+     */
+    pub(crate) fn subscribe(&mut self, keys: &[String]) -> UnboundedReceiver<SetReply> {
+        let (sender, receiver) = unbounded_channel();
+        for key in keys {
+            self.watchers.entry(key.clone()).or_default().push(sender.clone());
+        }
+        receiver
+    }
+
+    /**
+     * Keys with at least one live subscription, which need a `SetNotify` on every new connection
+     */
+    #[cfg(feature = "native")]
+    pub(crate) fn watched_keys(&mut self) -> Vec<String> {
+        self.watchers.retain(|_, watchers| {
+            watchers.retain(|watcher| !watcher.is_closed());
+            !watchers.is_empty()
+        });
+        self.watchers.keys().cloned().collect()
+    }
+
+    /**
+     * Hand a reply to the request waiting for it. Messages that should also be seen as events are returned
+     */
+    pub(crate) fn resolve(&mut self, message: ServerMessage) -> Option<ServerMessage> {
+        match message {
+            ServerMessage::Retrieved(retrieved) => match self.retrieved.pop_front() {
+                Some(waiting) => {
+                    let _ = waiting.send(retrieved);
+                    None
+                }
+                None => Some(ServerMessage::Retrieved(retrieved)),
+            },
+            ServerMessage::SetReply(reply) => {
+                if let Some(waiting) = set_tag(&reply).and_then(|tag| self.set_reply.remove(tag)) {
+                    let _ = waiting.send(reply.clone());
+                }
+                if let Some(watchers) = self.watchers.get_mut(reply.key()) {
+                    watchers.retain(|watcher| watcher.send(reply.clone()).is_ok());
+                }
+                Some(ServerMessage::SetReply(reply))
+            }
+            ServerMessage::LocationInfo(info) => match self.location_info.pop_front() {
+                Some(waiting) => {
+                    let _ = waiting.send(info);
+                    None
+                }
+                None => Some(ServerMessage::LocationInfo(info)),
+            },
+            ServerMessage::DataPackage(package) => match self.data_package.pop_front() {
+                Some(waiting) => {
+                    let _ = waiting.send(package);
+                    None
+                }
+                None => Some(ServerMessage::DataPackage(package)),
+            },
+            message => Some(message),
+        }
+    }
+
+    /**
+     * Fail every waiting request and start a new generation, used once the connection they were sent on is gone.
+     * Subscriptions are kept
+     */
+    #[cfg(feature = "native")]
+    pub(crate) fn clear(&mut self) {
+        *self = Pending {
+            watchers: std::mem::take(&mut self.watchers),
+            generation: self.generation + 1,
+            closed: self.closed,
+            ..Pending::default()
+        };
+    }
+
+    /**
+     * Fail every waiting request and every request made from now on, and end every subscription
+     */
+    pub(crate) fn close(&mut self) {
+        *self = Pending { closed: true, ..Pending::default() };
+    }
+}
+
+pub(crate) enum Awaiting {
+    Retrieved(oneshot::Receiver<Retrieved>),
+    SetReply(oneshot::Receiver<SetReply>),
+    LocationInfo(oneshot::Receiver<LocationInfo>),
+    DataPackage(oneshot::Receiver<DataPackage>),
+}
+
+macro_rules! await_reply {
+    ($reply: expr, $variant: ident) => {
+        match $reply {
+            Some(Awaiting::$variant(receiver)) => receiver.await.map_err(|_| Error::ConnectionClosed),
+            _ => unreachable!(),
+        }
+    };
+}
+#[cfg(feature = "native")]
+pub(crate) use await_reply;
+
+/**
+ * A split connection where the receiving side is owned by a background task.
+ *
+ * Replies to `get`, `set`, `location_scouts` and `get_data_package` are routed back to the call that sent the
+ * request, so these helpers are available again, and `subscribe` yields updates to watched data storage keys. Every
+ * other message is sent to the event receiver returned by `Client::dispatch`. `Dispatcher` can be cloned to send from
+ * several places at once.
+ */
+pub struct Dispatcher<T = BoxTransport> {
+    sender: Arc<AsyncMutex<ClientSender<T>>>,
+    pending: Arc<Mutex<Pending>>,
+}
+
+impl<T> Clone for Dispatcher<T> {
+    fn clone(&self) -> Self {
+        Dispatcher {
+            sender: self.sender.clone(),
+            pending: self.pending.clone(),
+        }
+    }
+}
+
+impl<T: Transport + Send> Client<T> {
+    /**
+     * Split the client and start routing replies, see `Dispatcher`.
+     *
+     * The event receiver is closed once the connection is lost, at which point waiting requests fail with `Error::ConnectionClosed`.
+     * The connection is kept alive with pings as configured by the client's `ConnectOptions`, and counts as lost once the
+     * server has been silent for longer than the keepalive timeout
+     */
+    pub fn dispatch(self) -> (Dispatcher<T>, UnboundedReceiver<ServerMessage>) {
+        let keepalive = self.keepalive();
+        let (sender, mut receiver) = self.split();
+        receiver.set_idle_timeout(keepalive.map(|keepalive| keepalive.timeout));
+        let sender = Arc::new(AsyncMutex::new(sender));
+        let pending = Arc::new(Mutex::new(Pending::default()));
+        let (event_sender, event_receiver) = unbounded_channel();
+
+        let task_sender = sender.clone();
+        let task_pending = pending.clone();
+        spawn(async move {
+            let mut ping = Keepalive::interval(keepalive);
+            loop {
+                select! {
+                    message = receiver.recv() => match message {
+                        Ok(Some(message)) => {
+                            let event = task_pending.lock().unwrap().resolve(message);
+                            if let Some(event) = event {
+                                let _ = event_sender.send(event);
+                            }
+                        }
+                        Ok(None) => break,
+                        Err(err) if err.is_connection_lost() => break,
+                        Err(_) => (),
+                    },
+                    _ = Keepalive::tick(&mut ping) => {
+                        if task_sender.lock().await.ping().await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+
+            task_pending.lock().unwrap().close();
+        });
+
+        (Dispatcher { sender, pending }, event_receiver)
+    }
+}
+
+impl<T: Transport> Dispatcher<T> {
+    pub async fn send(&self, message: ClientMessage) -> Result<(), Error> {
+        self.sender.lock().await.send(message).await
+    }
+
+    /**
+     * Send several messages in a single frame, see `Client::send_batch`. Like with `send`, replies to them are events
+     */
+    pub async fn send_batch(&self, messages: Vec<ClientMessage>) -> Result<(), Error> {
+        self.sender.lock().await.send_batch(messages).await
+    }
+
+    async fn request(&self, mut message: ClientMessage) -> Result<Option<Awaiting>, Error> {
+        // Registering and sending under the same lock keeps waiting requests in the order they were sent
+        let mut sender = self.sender.lock().await;
+        let reply = {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.closed {
+                return Err(Error::ConnectionClosed);
+            }
+            pending.register(&mut message)
+        };
+        sender.send(message).await?;
+        Ok(reply)
+    }
+
+    pub async fn say(&self, message: &str) -> Result<(), Error> {
+        self.send(ClientMessage::Say(Say { text: message.into() })).await
+    }
+
+    pub async fn location_checks(&self, locations: Vec<i64>) -> Result<(), Error> {
+        self.send(ClientMessage::LocationChecks(LocationChecks { locations })).await
+    }
+
+    pub async fn status_update(&self, status: ClientStatus) -> Result<(), Error> {
+        self.send(ClientMessage::StatusUpdate(StatusUpdate { status })).await
+    }
+
+    pub async fn bounce(&self, games: Option<Vec<String>>, slots: Option<Vec<String>>, tags: Option<Vec<String>>, data: serde_json::Value) -> Result<(), Error> {
+        self.send(ClientMessage::Bounce(Bounce { games, slots, tags, data })).await
+    }
+
+    pub async fn get_data_package(&self, games: Option<Box<[String]>>) -> Result<DataPackage, Error> {
+        await_reply!(self.request(ClientMessage::GetDataPackage(GetDataPackage { games })).await?, DataPackage)
+    }
+
+    pub async fn location_scouts(&self, locations: Vec<i64>, create_as_hint: i32) -> Result<LocationInfo, Error> {
+        await_reply!(self.request(ClientMessage::LocationScouts(LocationScouts { locations, create_as_hint })).await?, LocationInfo)
+    }
+
+    pub async fn get(&self, keys: Vec<String>) -> Result<Retrieved, Error> {
+        await_reply!(self.request(ClientMessage::Get(Get { keys })).await?, Retrieved)
+    }
+
+    /**
+     * Write to the server's data storage and wait for the `SetReply`. To write without waiting, `send` a `Set` instead
+     */
+    pub async fn set(&self, key: String, default: serde_json::Value, operations: Vec<DataStorageOperation>) -> Result<SetReply, Error> {
+        await_reply!(
+            self.request(ClientMessage::Set(Set {
+                key,
+                default,
+                want_reply: true,
+                operations,
+                extra: serde_json::Map::new(),
+            }))
+            .await?,
+            SetReply
+        )
+    }
+
+    /**
+     * Watch data storage keys with `SetNotify`. Every `SetReply` for one of the keys is sent to the returned receiver
+     * until the connection is lost
+     */
+    pub async fn subscribe(&self, keys: Vec<String>) -> Result<UnboundedReceiver<SetReply>, Error> {
+        let mut sender = self.sender.lock().await;
+        let receiver = {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.closed {
+                return Err(Error::ConnectionClosed);
+            }
+            pending.subscribe(&keys)
+        };
+        sender.send(ClientMessage::SetNotify(SetNotify { keys })).await?;
+        Ok(receiver)
+    }
+}
@@ -0,0 +1,42 @@
+use std::{future::pending, time::Duration};
+use tokio::time::{interval_at, Instant, Interval};
+
+/**
+ * Pings sent every `interval` to keep the connection alive, and how long to go without any frame from the server
+ * before the connection is considered lost
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Keepalive {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Keepalive {
+    /**
+     * Timer for the next ping, the first one fires after one interval
+     */
+    pub(crate) fn interval(keepalive: Option<Keepalive>) -> Option<Interval> {
+        keepalive.map(|keepalive| interval_at(Instant::now() + keepalive.interval, keepalive.interval))
+    }
+
+    /**
+     * Wait for the next ping, forever if there's no keepalive
+     */
+    pub(crate) async fn tick(interval: &mut Option<Interval>) {
+        match interval {
+            Some(interval) => {
+                interval.tick().await;
+            }
+            None => pending().await,
+        }
+    }
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Keepalive {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
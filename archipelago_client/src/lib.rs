@@ -1,17 +1,39 @@
+mod dispatcher;
+mod keepalive;
+#[cfg(feature = "native")]
+mod options;
+#[cfg(feature = "native")]
+mod recording;
+#[cfg(feature = "native")]
+mod supervisor;
+mod transport;
+
 use archipelago_protocol::{
     network_version, Bounce, ClientMessage, ClientStatus, Connect, Connected, DataPackage, DataStorageOperation, Get, GetDataPackage, LocationChecks, LocationInfo, LocationScouts, ReceivedItems,
-    Retrieved, RoomInfo, Say, ServerMessage, Set, SetReply, StatusUpdate,
+    RefusalReason, Retrieved, RoomInfo, Say, ServerMessage, Set, SetNotify, SetReply, StatusUpdate,
 };
 use futures_util::{
     stream::{SplitSink, SplitStream},
     SinkExt, Stream, StreamExt,
 };
+use std::{collections::VecDeque, fmt, ops::ControlFlow, time::Duration};
 use thiserror::Error;
-use tokio::net::TcpStream;
-use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use tokio::time::{timeout_at, Instant};
+#[cfg(feature = "native")]
 use tungstenite::protocol::Message;
 
+pub use dispatcher::Dispatcher;
+pub use keepalive::Keepalive;
+#[cfg(feature = "native")]
+pub use options::{ConnectOptions, Proxy, Scheme, DEFAULT_PORT};
+#[cfg(feature = "native")]
+pub use recording::{Direction, RecordedFrame, RecordedMessage};
+#[cfg(feature = "native")]
+pub use supervisor::{supervise, Backoff, ConnectInfo, ConnectionStatus, Event, SupervisedSender};
+pub use transport::{BoxTransport, Frame, MemoryTransport, Transport};
+
 #[derive(Error, Debug)]
+#[allow(clippy::large_enum_variant)] // Without the native errors, a whole message is by far the largest
 pub enum Error {
     #[error("illegal response")]
     IllegalResponse { received: ServerMessage, expected: &'static str },
@@ -19,29 +41,100 @@ pub enum Error {
     ConnectionClosed,
     #[error("data failed to serialize")]
     FailedSerialize(#[from] serde_json::Error),
+    #[cfg(feature = "native")]
     #[error("unexpected non-text result from websocket")]
     NonTextWebsocketResult(Message),
+    #[cfg(feature = "native")]
     #[error("network error")]
     NetworkError(#[from] tungstenite::Error),
+    #[error("connection refused by server: {0:?}")]
+    ConnectionRefused(Vec<RefusalReason>),
+    #[error("invalid server address {0}")]
+    InvalidAddress(String),
+    #[error("timed out")]
+    Timeout,
+    #[cfg(feature = "native")]
+    #[error("tls error")]
+    Tls(#[from] native_tls::Error),
+    #[error("proxy error: {0}")]
+    Proxy(String),
+    #[error("io error")]
+    Io(#[from] std::io::Error),
+    #[error("invalid recording, {0}")]
+    InvalidRecording(String),
+    #[error("the replay is over")]
+    ReplayFinished,
+    #[error("server sent a malformed {cmd} message")]
+    MalformedMessage { cmd: String, raw: serde_json::Value },
+}
+
+impl Error {
+    /**
+     * Whether the connection can no longer be used after this error
+     */
+    pub fn is_connection_lost(&self) -> bool {
+        match self {
+            Error::ConnectionClosed | Error::Timeout => true,
+            #[cfg(feature = "native")]
+            Error::NetworkError(_) => true,
+            _ => false,
+        }
+    }
 }
 
 /**
  * A convenience layer to manage your connection to and communication with Archipelago
+ *
+ * Works over any `Transport`, by default the websocket or replay opened by `connect_with`
  */
-#[allow(clippy::module_name_repetitions)]
-#[derive(Debug)]
-pub struct Client {
-    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
-    message_buffer: Vec<ServerMessage>,
+pub struct Client<T = BoxTransport> {
+    ws: T,
+    message_buffer: VecDeque<ServerMessage>,
+    url: String,
+    keepalive: Option<Keepalive>,
 }
 
+// Transports don't have to be `Debug`, a boxed one can't be
+impl<T> fmt::Debug for Client<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("message_buffer", &self.message_buffer)
+            .field("url", &self.url)
+            .field("keepalive", &self.keepalive)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "native")]
 impl Client {
     /**
      * Create an instance of the client and connect to the server on the given URL
      */
     pub async fn new(url: &str) -> Result<(Client, RoomInfo), Error> {
-        let (mut ws, _) = connect_async(url).await?;
-        let response = recv_messages(&mut ws).await.ok_or(Error::ConnectionClosed)??;
+        Client::connect_with(&ConnectOptions::new(url)).await
+    }
+
+    /**
+     * Create an instance of the client and connect to the server as described by `options`
+     */
+    pub async fn connect_with(options: &ConnectOptions) -> Result<(Client, RoomInfo), Error> {
+        let (ws, url) = options.open().await?;
+        let (mut client, room_info) = options::with_timeout(options.handshake_timeout, Client::with_transport(ws)).await?;
+        client.url = url;
+        client.keepalive = options.keepalive;
+
+        Ok((client, room_info))
+    }
+}
+
+impl<T: Transport> Client<T> {
+    /**
+     * Create an instance of the client over an already open transport, waiting for the server's `RoomInfo`
+     *
+     * The client has no url and no keepalive, it's up to the transport to notice a dead connection
+     */
+    pub async fn with_transport(mut ws: T) -> Result<(Client<T>, RoomInfo), Error> {
+        let response = recv_messages(&mut ws, None).await.ok_or(Error::ConnectionClosed)??;
         let mut iter = response.into_iter();
         let room_info = match iter.next() {
             Some(ServerMessage::RoomInfo(room)) => room,
@@ -54,24 +147,53 @@ impl Client {
             None => return Err(Error::ConnectionClosed),
         };
 
-        Ok((Client { ws, message_buffer: iter.collect() }, room_info))
+        Ok((
+            Client {
+                ws,
+                message_buffer: iter.collect(),
+                url: String::new(),
+                keepalive: None,
+            },
+            room_info,
+        ))
+    }
+
+    /**
+     * Keepalive from the options the client was created with. Only used by `dispatch` and `supervise`, which send the pings
+     */
+    pub fn keepalive(&self) -> Option<Keepalive> {
+        self.keepalive
+    }
+
+    /**
+     * The url the client is connected to, including the scheme that worked. The address as given for a replay
+     */
+    pub fn url(&self) -> &str {
+        &self.url
     }
 
     pub async fn get_data_package(&mut self, games: Option<Box<[String]>>) -> Result<DataPackage, Error> {
         self.send(ClientMessage::GetDataPackage(GetDataPackage { games })).await?;
-        let response = self.recv().await?;
-        match response {
-            Some(ServerMessage::DataPackage(pkg)) => Ok(pkg),
-            Some(received) => Err(Error::IllegalResponse { received, expected: "DataPackage" }),
-            None => Err(Error::ConnectionClosed),
-        }
+        self.recv_reply(|response| match response {
+            ServerMessage::DataPackage(pkg) => ControlFlow::Break(pkg),
+            resp => ControlFlow::Continue(resp),
+        })
+        .await
     }
 
     pub async fn send(&mut self, message: ClientMessage) -> Result<(), Error> {
-        let request = serde_json::to_string(&[message])?;
-        self.ws.send(Message::Text(request)).await?;
+        self.send_batch(vec![message]).await
+    }
 
-        Ok(())
+    /**
+     * Send several messages in a single frame, so the server gets either all of them or none. An empty batch sends nothing
+     */
+    pub async fn send_batch(&mut self, messages: Vec<ClientMessage>) -> Result<(), Error> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+        let request = serde_json::to_string(&messages)?;
+        self.ws.send(Frame::Text(request)).await
     }
 
     /**
@@ -81,26 +203,44 @@ impl Client {
      * if buffer is empty
      */
     pub async fn recv(&mut self) -> Result<Option<ServerMessage>, Error> {
-        if let Some(message) = self.message_buffer.pop() {
+        if let Some(message) = self.message_buffer.pop_front() {
             return Ok(Some(message));
         }
-        let messages = recv_messages(&mut self.ws).await;
+        let messages = recv_messages(&mut self.ws, None).await;
         if let Some(result) = messages {
-            let mut messages = result?;
-            messages.reverse();
-            let first = messages.pop();
-            self.message_buffer = messages;
-            Ok(first)
+            self.message_buffer.extend(result?);
+            Ok(self.message_buffer.pop_front())
         } else {
             Ok(None)
         }
     }
 
+    /**
+     * Take the first message `reply` breaks on, from the buffer or else from the network
+     *
+     * Every message `reply` continues with stays buffered, in the order the server sent it, for `recv`
+     */
+    async fn recv_reply<R>(&mut self, reply: impl Fn(ServerMessage) -> ControlFlow<R, ServerMessage>) -> Result<R, Error> {
+        let mut pending = std::mem::take(&mut self.message_buffer);
+        loop {
+            while let Some(message) = pending.pop_front() {
+                match reply(message) {
+                    ControlFlow::Break(found) => {
+                        self.message_buffer.append(&mut pending);
+                        return Ok(found);
+                    }
+                    ControlFlow::Continue(message) => self.message_buffer.push_back(message),
+                }
+            }
+            pending.extend(recv_messages(&mut self.ws, None).await.ok_or(Error::ConnectionClosed)??);
+        }
+    }
+
     /**
      * Send a connect request to the Archipelago server
      *
-     * Will attempt to read a Connected packet in response, and will return an error if
-     * another packet is found
+     * Will wait for a Connected packet in response, buffering anything else the server sends before it. A
+     * `ConnectionRefused` packet is returned as `Error::ConnectionRefused`
      */
     pub async fn connect(&mut self, game: &str, name: &str, uuid: &str, pass: Option<&str>, items_handling: Option<i32>, tags: &[String]) -> Result<Connected, Error> {
         self.send(ClientMessage::Connect(Connect {
@@ -113,12 +253,13 @@ impl Client {
             tags: tags.into(),
         }))
         .await?;
-        let response = self.recv().await?.ok_or(Error::ConnectionClosed)?;
 
-        match response {
-            ServerMessage::Connected(connected) => Ok(connected),
-            received => Err(Error::IllegalResponse { received, expected: "Connected" }),
-        }
+        self.recv_reply(|response| match response {
+            ServerMessage::Connected(connected) => ControlFlow::Break(Ok(connected)),
+            ServerMessage::ConnectionRefused(refused) => ControlFlow::Break(Err(Error::ConnectionRefused(refused.reasons()))),
+            resp => ControlFlow::Continue(resp),
+        })
+        .await?
     }
 
     /**
@@ -135,14 +276,11 @@ impl Client {
      */
     pub async fn sync(&mut self) -> Result<ReceivedItems, Error> {
         self.send(ClientMessage::Sync).await?;
-        while let Some(response) = self.recv().await? {
-            match response {
-                ServerMessage::ReceivedItems(items) => return Ok(items),
-                resp => self.message_buffer.push(resp),
-            }
-        }
-
-        Err(Error::ConnectionClosed)
+        self.recv_reply(|response| match response {
+            ServerMessage::ReceivedItems(items) => ControlFlow::Break(items),
+            resp => ControlFlow::Continue(resp),
+        })
+        .await
     }
 
     /**
@@ -161,14 +299,11 @@ impl Client {
      */
     pub async fn location_scouts(&mut self, locations: Vec<i64>, create_as_hint: i32) -> Result<LocationInfo, Error> {
         self.send(ClientMessage::LocationScouts(LocationScouts { locations, create_as_hint })).await?;
-        while let Some(response) = self.recv().await? {
-            match response {
-                ServerMessage::LocationInfo(items) => return Ok(items),
-                resp => self.message_buffer.push(resp),
-            }
-        }
-
-        Err(Error::ConnectionClosed)
+        self.recv_reply(|response| match response {
+            ServerMessage::LocationInfo(items) => ControlFlow::Break(items),
+            resp => ControlFlow::Continue(resp),
+        })
+        .await
     }
 
     /**
@@ -195,14 +330,11 @@ impl Client {
      */
     pub async fn get(&mut self, keys: Vec<String>) -> Result<Retrieved, Error> {
         self.send(ClientMessage::Get(Get { keys })).await?;
-        while let Some(response) = self.recv().await? {
-            match response {
-                ServerMessage::Retrieved(items) => return Ok(items),
-                resp => self.message_buffer.push(resp),
-            }
-        }
-
-        Err(Error::ConnectionClosed)
+        self.recv_reply(|response| match response {
+            ServerMessage::Retrieved(items) => ControlFlow::Break(items),
+            resp => ControlFlow::Continue(resp),
+        })
+        .await
     }
 
     /**
@@ -211,15 +343,30 @@ impl Client {
      * Values for keys in the data storage can be retrieved with a Get package, or monitored with a `SetNotify` package. Non-SetReply responses are buffered
      */
     pub async fn set(&mut self, key: String, default: serde_json::Value, want_reply: bool, operations: Vec<DataStorageOperation>) -> Result<SetReply, Error> {
-        self.send(ClientMessage::Set(Set { key, default, want_reply, operations })).await?;
-        while let Some(response) = self.recv().await? {
-            match response {
-                ServerMessage::SetReply(items) => return Ok(items),
-                resp => self.message_buffer.push(resp),
-            }
-        }
+        let mut set = Set {
+            key,
+            default,
+            want_reply,
+            operations,
+            extra: serde_json::Map::new(),
+        };
+        // Others' changes to a watched key are sent as SetReply as well
+        let tag = dispatcher::tag_set(&mut set);
+        self.send(ClientMessage::Set(set)).await?;
+        self.recv_reply(|response| match response {
+            ServerMessage::SetReply(reply) if dispatcher::set_tag(&reply) == Some(&tag) => ControlFlow::Break(reply),
+            resp => ControlFlow::Continue(resp),
+        })
+        .await
+    }
 
-        Err(Error::ConnectionClosed)
+    /**
+     * Used to register your current session for receiving all `SetReply` packages of certain keys to allow your client to keep track of changes.
+     *
+     * The updates arrive through `recv`, use `Dispatcher::subscribe` to receive them separately
+     */
+    pub async fn set_notify(&mut self, keys: Vec<String>) -> Result<(), Error> {
+        self.send(ClientMessage::SetNotify(SetNotify { keys })).await
     }
 
     /**
@@ -227,12 +374,21 @@ impl Client {
      *
      * This removes access to a few convenience methods (like `get` or `set`) because it's
      * there's now extra coordination required to match a read and write, but it brings
-     * the benefits of allowing simultaneous reading and writing.
+     * the benefits of allowing simultaneous reading and writing. Use `dispatch` to split
+     * while keeping those methods.
      */
-    pub fn split(self) -> (ClientSender, ClientReceiver) {
-        let Self { ws, message_buffer } = self;
+    pub fn split(self) -> (ClientSender<T>, ClientReceiver<T>) {
+        let Self { ws, message_buffer, .. } = self;
         let (send, recv) = ws.split();
-        (ClientSender { ws: send }, ClientReceiver { ws: recv, message_buffer })
+        (
+            ClientSender { ws: send },
+            ClientReceiver {
+                ws: recv,
+                message_buffer,
+                idle_timeout: None,
+                last_frame: Instant::now(),
+            },
+        )
     }
 }
 
@@ -243,16 +399,21 @@ impl Client {
  * both sending and receiving are intentionally unavailable; for those messages,
  * use `send`.
  */
-pub struct ClientSender {
-    ws: SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>,
+pub struct ClientSender<T = BoxTransport> {
+    ws: SplitSink<T, Frame>,
 }
 
-impl ClientSender {
+impl<T: Transport> ClientSender<T> {
     pub async fn send(&mut self, message: ClientMessage) -> Result<(), Error> {
-        let request = serde_json::to_string(&[message])?;
-        self.ws.send(Message::Text(request)).await?;
+        self.send_batch(vec![message]).await
+    }
 
-        Ok(())
+    pub async fn send_batch(&mut self, messages: Vec<ClientMessage>) -> Result<(), Error> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+        let request = serde_json::to_string(&messages)?;
+        self.ws.send(Frame::Text(request)).await
     }
 
     pub async fn say(&mut self, message: &str) -> Result<(), Error> {
@@ -270,6 +431,17 @@ impl ClientSender {
     pub async fn bounce(&mut self, games: Option<Vec<String>>, slots: Option<Vec<String>>, tags: Option<Vec<String>>, data: serde_json::Value) -> Result<(), Error> {
         self.send(ClientMessage::Bounce(Bounce { games, slots, tags, data })).await
     }
+
+    pub async fn set_notify(&mut self, keys: Vec<String>) -> Result<(), Error> {
+        self.send(ClientMessage::SetNotify(SetNotify { keys })).await
+    }
+
+    /**
+     * Send a websocket ping. The server's pong is consumed by `ClientReceiver::recv`, but counts as activity for its idle timeout
+     */
+    pub async fn ping(&mut self) -> Result<(), Error> {
+        self.ws.send(Frame::Ping(vec![])).await
+    }
 }
 
 /**
@@ -279,34 +451,79 @@ impl ClientSender {
  * both sending and receiving are intentionally unavailable; for those messages,
  * use `recv`.
  */
-pub struct ClientReceiver {
-    ws: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
-    message_buffer: Vec<ServerMessage>,
+pub struct ClientReceiver<T = BoxTransport> {
+    ws: SplitStream<T>,
+    message_buffer: VecDeque<ServerMessage>,
+    idle_timeout: Option<Duration>,
+    last_frame: Instant,
 }
 
-impl ClientReceiver {
+impl<T: Transport> ClientReceiver<T> {
+    /**
+     * Fail `recv` with `Error::Timeout` once no frame at all arrived for `idle_timeout`. Only useful while pinging the
+     * server more often than that, otherwise a quiet room looks like a dead connection
+     */
+    pub fn set_idle_timeout(&mut self, idle_timeout: Option<Duration>) {
+        self.idle_timeout = idle_timeout;
+        self.last_frame = Instant::now();
+    }
+
     pub async fn recv(&mut self) -> Result<Option<ServerMessage>, Error> {
-        if let Some(message) = self.message_buffer.pop() {
+        if let Some(message) = self.message_buffer.pop_front() {
             return Ok(Some(message));
         }
-        let messages = recv_messages(&mut self.ws).await;
+        // Measured from the last frame rather than from this call, so a cancelled recv doesn't reset the timeout
+        let idle = self.idle_timeout.map(|idle_timeout| (idle_timeout, &mut self.last_frame));
+        let messages = recv_messages(&mut self.ws, idle).await;
         if let Some(result) = messages {
-            let mut messages = result?;
-            messages.reverse();
-            let first = messages.pop();
-            self.message_buffer = messages;
-            Ok(first)
+            self.message_buffer.extend(result?);
+            Ok(self.message_buffer.pop_front())
         } else {
             Ok(None)
         }
     }
 }
 
-async fn recv_messages(mut ws: impl Stream<Item = Result<Message, tungstenite::error::Error>> + std::marker::Unpin) -> Option<Result<Vec<ServerMessage>, Error>> {
-    match ws.next().await? {
-        Ok(Message::Text(response)) => Some(serde_json::from_str::<Vec<ServerMessage>>(&response).map_err(Into::into)),
-        Ok(Message::Close(_)) => Some(Err(Error::ConnectionClosed)),
-        Ok(msg) => Some(Err(Error::NonTextWebsocketResult(msg))),
-        Err(e) => Some(Err(e.into())),
+async fn recv_messages(mut ws: impl Stream<Item = Result<Frame, Error>> + std::marker::Unpin, mut idle: Option<(Duration, &mut Instant)>) -> Option<Result<Vec<ServerMessage>, Error>> {
+    loop {
+        let frame = match &mut idle {
+            Some((idle_timeout, last_frame)) => match timeout_at(**last_frame + *idle_timeout, ws.next()).await {
+                Ok(frame) => {
+                    **last_frame = Instant::now();
+                    frame?
+                }
+                Err(_) => return Some(Err(Error::Timeout)),
+            },
+            None => ws.next().await?,
+        };
+
+        return match frame {
+            Ok(Frame::Text(response)) => Some(decode_messages(&response)),
+            Ok(Frame::Close) => Some(Err(Error::ConnectionClosed)),
+            // Pings are answered by the transport, tungstenite does it for websockets, and pongs only matter as a sign of life
+            Ok(Frame::Ping(_) | Frame::Pong(_)) => continue,
+            Err(e) => Some(Err(e)),
+        };
+    }
+}
+
+/**
+ * Decode a frame one message at a time, so a message this client doesn't understand doesn't take the rest of the
+ * frame down with it. Such messages are logged and passed on as `ServerMessage::Unknown`. A known message that fails to
+ * decode is an error though, skipping it would silently lose items or checks
+ */
+#[allow(clippy::result_large_err)]
+fn decode_messages(frame: &str) -> Result<Vec<ServerMessage>, Error> {
+    let messages = serde_json::from_str::<Vec<ServerMessage>>(frame)?;
+    for message in &messages {
+        if let ServerMessage::Unknown { cmd, raw } = message {
+            if ServerMessage::is_known_command(cmd) {
+                log::error!("Could not decode {cmd:?} message from server: {raw}");
+                return Err(Error::MalformedMessage { cmd: cmd.clone(), raw: raw.clone() });
+            }
+            log::warn!("Ignoring unknown {cmd:?} message from server: {raw}");
+        }
     }
+
+    Ok(messages)
 }
@@ -0,0 +1,325 @@
+use crate::{Error, Frame};
+use futures_util::{Sink, Stream};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// Sent by the server
+    In,
+    /// Sent by the client
+    Out,
+}
+
+/**
+ * The kind of frame, and its payload for text frames
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RecordedMessage {
+    Text { text: String },
+    Ping,
+    Pong,
+    Close,
+}
+
+impl From<&Frame> for RecordedMessage {
+    fn from(frame: &Frame) -> Self {
+        match frame {
+            Frame::Text(text) => RecordedMessage::Text { text: text.clone() },
+            Frame::Ping(_) => RecordedMessage::Ping,
+            Frame::Pong(_) => RecordedMessage::Pong,
+            Frame::Close => RecordedMessage::Close,
+        }
+    }
+}
+
+/**
+ * One line of a recording
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedFrame {
+    /// Milliseconds since the unix epoch
+    pub time: u64,
+    pub direction: Direction,
+    #[serde(flatten)]
+    pub message: RecordedMessage,
+}
+
+/**
+ * Appends every frame of a connection to a JSONL file, one `RecordedFrame` per line. Recordings are meant to be shared,
+ * so the room password is blanked out of outgoing `Connect` messages
+ */
+#[derive(Debug)]
+pub(crate) struct Recorder {
+    file: File,
+}
+
+impl Recorder {
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn open(path: &Path) -> Result<Recorder, Error> {
+        Ok(Recorder {
+            file: OpenOptions::new().create(true).append(true).open(path)?,
+        })
+    }
+
+    /**
+     * Failing to record is logged rather than returned, a full disk shouldn't take the connection down with it
+     */
+    pub(crate) fn record(&mut self, direction: Direction, frame: &Frame) {
+        let mut message = RecordedMessage::from(frame);
+        if let (Direction::Out, RecordedMessage::Text { text }) = (direction, &mut message) {
+            if let Some(redacted) = redact_password(text) {
+                *text = redacted;
+            }
+        }
+        let recorded = RecordedFrame {
+            time: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_millis() as u64),
+            direction,
+            message,
+        };
+        let mut line = serde_json::to_string(&recorded).expect("recorded frames always serialize");
+        line.push('\n');
+        if let Err(err) = self.file.write_all(line.as_bytes()) {
+            log::warn!("Failed to record frame: {err}");
+        }
+    }
+}
+
+/**
+ * The frame with the password of every `Connect` in it blanked, if there is one
+ */
+fn redact_password(text: &str) -> Option<String> {
+    let mut messages = serde_json::from_str::<Vec<Value>>(text).ok()?;
+    let mut redacted = false;
+    for message in &mut messages {
+        if message["cmd"] != "Connect" {
+            continue;
+        }
+        if let Some(password) = message.get_mut("password").filter(|password| password.as_str().is_some_and(|password| !password.is_empty())) {
+            *password = Value::from("");
+            redacted = true;
+        }
+    }
+
+    redacted.then(|| serde_json::to_string(&messages).expect("decoded messages always serialize"))
+}
+
+/**
+ * Plays the server's side of a recording back, as fast as the client reads it
+ *
+ * Replies are matched to what the client actually sends rather than played in file order, so a client that e.g. has
+ * the datapackage cached when the recording didn't still gets the right answers. Each request gets the first recorded
+ * reply to a request with the same `cmd`, replies nobody asks for are left out, and everything else the server sent
+ * is played in order, with what came after a `Connected` held back until the client connects. Pings are answered so
+ * a keepalive doesn't give up on the replay, the rest of what the client sends is dropped.
+ *
+ * Once the recording is over the replay fails with `Error::ReplayFinished`, which unlike a closed connection isn't worth
+ * reconnecting for. The server closing the connection in the recording ends nothing, a recording can go on with the
+ * next connection.
+ */
+#[derive(Debug)]
+pub(crate) struct Replay {
+    /// Answers to what the client sent, which go ahead of everything else
+    answers: VecDeque<Frame>,
+    /// The rest of what the server sent, with the number of `Connect`s the client has to send before each frame
+    frames: VecDeque<(usize, Frame)>,
+    /// Recorded replies, by the `cmd` of the request they answer
+    replies: HashMap<String, VecDeque<Value>>,
+    connects: usize,
+    /// Requests the recording has no reply to
+    unanswered: Vec<String>,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+impl Replay {
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn open(path: &Path) -> Result<Replay, Error> {
+        let recording = fs::read_to_string(path)?;
+        let mut frames = VecDeque::new();
+        let mut replies: HashMap<String, VecDeque<Value>> = HashMap::new();
+        // Requests the client sent that are still waiting for their reply
+        let mut requests: HashMap<String, usize> = HashMap::new();
+        let mut connects = 0;
+
+        for (number, line) in recording.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+            let invalid = |err: serde_json::Error| Error::InvalidRecording(format!("line {}: {err}", number + 1));
+            let frame: RecordedFrame = serde_json::from_str(line).map_err(invalid)?;
+            let RecordedMessage::Text { text } = frame.message else {
+                continue;
+            };
+            let messages: Vec<Value> = serde_json::from_str(&text).map_err(invalid)?;
+
+            if frame.direction == Direction::Out {
+                for request in messages.iter().filter_map(reply_expected) {
+                    *requests.entry(request.to_string()).or_default() += 1;
+                }
+                continue;
+            }
+
+            let mut rest = vec![];
+            for message in messages {
+                let waiting = message["cmd"]
+                    .as_str()
+                    .and_then(request_answered)
+                    .and_then(|request| requests.get_mut(request).filter(|waiting| **waiting > 0).map(|waiting| (request, waiting)));
+                match waiting {
+                    Some((request, waiting)) => {
+                        *waiting -= 1;
+                        connects += usize::from(request == "Connect");
+                        replies.entry(request.to_string()).or_default().push_back(message);
+                    }
+                    None => rest.push(message),
+                }
+            }
+            if !rest.is_empty() {
+                frames.push_back((connects, Frame::Text(Value::from(rest).to_string())));
+            }
+        }
+
+        Ok(Replay {
+            answers: VecDeque::new(),
+            frames,
+            replies,
+            connects: 0,
+            unanswered: vec![],
+            waker: None,
+            closed: false,
+        })
+    }
+
+    fn answer(&mut self, text: &str) {
+        let Ok(messages) = serde_json::from_str::<Vec<Value>>(text) else {
+            return;
+        };
+
+        for request in &messages {
+            let Some(cmd) = reply_expected(request) else {
+                continue;
+            };
+            self.connects += usize::from(cmd == "Connect");
+            match self.replies.get_mut(cmd).and_then(VecDeque::pop_front) {
+                Some(mut reply) => {
+                    if cmd == "Set" {
+                        echo_set_arguments(request, &mut reply);
+                    }
+                    self.answers.push_back(Frame::Text(Value::from(vec![reply]).to_string()));
+                }
+                None => {
+                    log::warn!("The recording has no reply to {cmd}");
+                    self.unanswered.push(cmd.to_string());
+                }
+            }
+        }
+    }
+}
+
+/**
+ * The `cmd` of a client message the server replies to
+ */
+fn reply_expected(message: &Value) -> Option<&str> {
+    match message["cmd"].as_str()? {
+        cmd @ ("GetDataPackage" | "Connect" | "Sync" | "LocationScouts" | "Get") => Some(cmd),
+        "Set" if message["want_reply"] == true => Some("Set"),
+        _ => None,
+    }
+}
+
+/**
+ * The `cmd` of the request a server message can be the reply to
+ */
+fn request_answered(cmd: &str) -> Option<&'static str> {
+    match cmd {
+        "DataPackage" => Some("GetDataPackage"),
+        "Connected" | "ConnectionRefused" => Some("Connect"),
+        "ReceivedItems" => Some("Sync"),
+        "LocationInfo" => Some("LocationScouts"),
+        "Retrieved" => Some("Get"),
+        "SetReply" => Some("Set"),
+        _ => None,
+    }
+}
+
+/**
+ * The server passes any extra arguments of a `Set` back in its `SetReply`, which is how replies find their request
+ */
+fn echo_set_arguments(set: &Value, reply: &mut Value) {
+    let (Some(set), Some(reply)) = (set.as_object(), reply.as_object_mut()) else {
+        return;
+    };
+    for (key, value) in set {
+        if !["cmd", "key", "default", "want_reply", "operations"].contains(&key.as_str()) {
+            reply.insert(key.clone(), value.clone());
+        }
+    }
+}
+
+impl Stream for Replay {
+    type Item = Result<Frame, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.closed {
+            return Poll::Ready(None);
+        }
+        if let Some(frame) = self.answers.pop_front() {
+            return Poll::Ready(Some(Ok(frame)));
+        }
+
+        let connected = self.connects;
+        if let Some((_, frame)) = self.frames.pop_front_if(|(connects, _)| *connects <= connected) {
+            return Poll::Ready(Some(Ok(frame)));
+        }
+
+        if self.frames.is_empty() {
+            return Poll::Ready(Some(Err(Error::ReplayFinished)));
+        }
+        // Held back until the client connects, which it won't while waiting for a reply that was never recorded
+        match self.unanswered.first() {
+            Some(cmd) => Poll::Ready(Some(Err(Error::InvalidRecording(format!("there is no reply to {cmd} in it"))))),
+            None => {
+                self.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Sink<Frame> for Replay {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, frame: Frame) -> Result<(), Error> {
+        match frame {
+            Frame::Text(text) => self.answer(&text),
+            Frame::Ping(payload) => self.answers.push_back(Frame::Pong(payload)),
+            Frame::Close => self.closed = true,
+            Frame::Pong(_) => (),
+        }
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,360 @@
+use crate::{
+    dispatcher::{await_reply, Awaiting, Pending},
+    Client, ConnectOptions, Error, Keepalive,
+};
+use archipelago_protocol::{
+    Bounce, ClientMessage, ClientStatus, Connected, DataPackage, DataStorageOperation, Get, GetDataPackage, LocationChecks, LocationInfo, LocationScouts, ReceivedItems, RefusalReason, Retrieved, Say,
+    ServerMessage, Set, SetNotify, SetReply, StatusUpdate,
+};
+use std::{
+    collections::BTreeSet,
+    iter::{from_fn, once},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    select, spawn,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    time::{sleep, timeout},
+};
+
+/**
+ * Everything needed to establish a session with the server again after the connection is lost. Use
+ * `ConnectOptions::with_address` and `Client::url` to keep the scheme that worked the first time
+ */
+#[derive(Debug, Clone)]
+pub struct ConnectInfo {
+    pub options: ConnectOptions,
+    pub game: String,
+    pub name: String,
+    pub uuid: String,
+    pub password: Option<String>,
+    pub items_handling: Option<i32>,
+    pub tags: Vec<String>,
+}
+
+/**
+ * Delays between reconnect attempts. The delay starts at `initial` and is multiplied by `factor` after
+ * every failed attempt, up to `max`. An attempt that takes longer than `attempt_timeout`, from opening the
+ * connection to getting the server's items, counts as failed
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub factor: u32,
+    pub attempt_timeout: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+            factor: 2,
+            attempt_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    Connected,
+    Reconnecting {
+        attempt: u32,
+    },
+    /**
+     * The server refused the session, which trying again won't change. The supervisor stops after this
+     */
+    Refused(Vec<RefusalReason>),
+    /**
+     * The replay the client was playing back is over, there is nothing to reconnect to. The supervisor stops after this
+     */
+    Ended,
+}
+
+#[allow(clippy::large_enum_variant)] // Most events are messages anyways
+#[derive(Debug)]
+pub enum Event {
+    Message(ServerMessage),
+    Status(ConnectionStatus),
+}
+
+/**
+ * Keep a connection alive, reconnecting with backoff whenever it drops.
+ *
+ * The connection counts as lost once it closes, or once the server has been silent for longer than the keepalive timeout
+ * of the client's `ConnectOptions`, which catches half-open connections. Messages sent through the returned
+ * `SupervisedSender` are queued while reconnecting, and everything queued by the time the supervisor gets to it is sent
+ * in a single frame. Location checks are
+ * re-sent on every reconnect until the server reports them as checked, and since `StatusUpdate` is never
+ * acknowledged, the last status is re-sent on every reconnect as well. After a reconnect, the new `Connected`
+ * and `ReceivedItems` packets are emitted as regular messages.
+ *
+ * Replies to requests made through the sender are routed back to the request like with `Dispatcher`. Requests
+ * waiting for a reply when the connection drops fail with `Error::ConnectionClosed`. Subscriptions outlive the
+ * connection, their keys are watched again after every reconnect.
+ *
+ * Reconnecting stops for good once the server refuses the session, e.g. because the password changed, which is
+ * reported as `ConnectionStatus::Refused`, or once a replay is over, which is reported as `ConnectionStatus::Ended`.
+ * Requests fail with `Error::ConnectionClosed` from then on.
+ *
+ * The supervisor stops once every sender or the event receiver is dropped.
+ */
+pub fn supervise(client: Client, info: ConnectInfo, backoff: Backoff) -> (SupervisedSender, UnboundedReceiver<Event>) {
+    let (outbound_sender, outbound_receiver) = unbounded_channel();
+    let (event_sender, event_receiver) = unbounded_channel();
+    let pending = Arc::new(Mutex::new(Pending::default()));
+
+    spawn(supervisor(client, info, backoff, outbound_receiver, event_sender, pending.clone()));
+
+    (SupervisedSender { outbound: outbound_sender, pending }, event_receiver)
+}
+
+/**
+ * Sending-side of a supervised connection. Sends never wait on the network and only fail once the
+ * supervisor has stopped.
+ */
+#[derive(Debug, Clone)]
+pub struct SupervisedSender {
+    outbound: UnboundedSender<Outbound>,
+    pending: Arc<Mutex<Pending>>,
+}
+
+/**
+ * Messages queued to be sent together. Requests carry the generation they were registered in, see `Pending`
+ */
+#[derive(Debug)]
+struct Outbound {
+    messages: Vec<ClientMessage>,
+    generation: Option<u64>,
+}
+
+#[allow(clippy::result_large_err)]
+impl SupervisedSender {
+    pub fn send(&self, message: ClientMessage) -> Result<(), Error> {
+        self.send_batch(vec![message])
+    }
+
+    /**
+     * Queue several messages to be sent in the same frame, so they reach the server together or not at all
+     */
+    pub fn send_batch(&self, messages: Vec<ClientMessage>) -> Result<(), Error> {
+        self.outbound.send(Outbound { messages, generation: None }).map_err(|_| Error::ConnectionClosed)
+    }
+
+    pub fn say(&self, message: &str) -> Result<(), Error> {
+        self.send(ClientMessage::Say(Say { text: message.into() }))
+    }
+
+    pub fn location_checks(&self, locations: Vec<i64>) -> Result<(), Error> {
+        self.send(ClientMessage::LocationChecks(LocationChecks { locations }))
+    }
+
+    pub fn status_update(&self, status: ClientStatus) -> Result<(), Error> {
+        self.send(ClientMessage::StatusUpdate(StatusUpdate { status }))
+    }
+
+    pub fn bounce(&self, games: Option<Vec<String>>, slots: Option<Vec<String>>, tags: Option<Vec<String>>, data: serde_json::Value) -> Result<(), Error> {
+        self.send(ClientMessage::Bounce(Bounce { games, slots, tags, data }))
+    }
+
+    fn request(&self, mut message: ClientMessage) -> Result<Option<Awaiting>, Error> {
+        let mut pending = self.pending.lock().unwrap();
+        let reply = pending.register(&mut message);
+        let generation = reply.is_some().then(|| pending.generation());
+        self.outbound.send(Outbound { messages: vec![message], generation }).map_err(|_| Error::ConnectionClosed)?;
+        Ok(reply)
+    }
+
+    pub async fn get_data_package(&self, games: Option<Box<[String]>>) -> Result<DataPackage, Error> {
+        await_reply!(self.request(ClientMessage::GetDataPackage(GetDataPackage { games }))?, DataPackage)
+    }
+
+    pub async fn location_scouts(&self, locations: Vec<i64>, create_as_hint: i32) -> Result<LocationInfo, Error> {
+        await_reply!(self.request(ClientMessage::LocationScouts(LocationScouts { locations, create_as_hint }))?, LocationInfo)
+    }
+
+    pub async fn get(&self, keys: Vec<String>) -> Result<Retrieved, Error> {
+        await_reply!(self.request(ClientMessage::Get(Get { keys }))?, Retrieved)
+    }
+
+    pub async fn set(&self, key: String, default: serde_json::Value, operations: Vec<DataStorageOperation>) -> Result<SetReply, Error> {
+        await_reply!(
+            self.request(ClientMessage::Set(Set {
+                key,
+                default,
+                want_reply: true,
+                operations,
+                extra: serde_json::Map::new(),
+            }))?,
+            SetReply
+        )
+    }
+
+    /**
+     * Watch data storage keys, see `Dispatcher::subscribe`. Updates made while reconnecting are missed
+     */
+    pub fn subscribe(&self, keys: Vec<String>) -> Result<UnboundedReceiver<SetReply>, Error> {
+        let mut pending = self.pending.lock().unwrap();
+        let receiver = pending.subscribe(&keys);
+        self.send(ClientMessage::SetNotify(SetNotify { keys }))?;
+        Ok(receiver)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Unconfirmed {
+    locations: BTreeSet<i64>,
+    status: Option<ClientStatus>,
+}
+
+impl Unconfirmed {
+    fn track(&mut self, message: &ClientMessage) {
+        match message {
+            ClientMessage::LocationChecks(checks) => self.locations.extend(&checks.locations),
+            ClientMessage::StatusUpdate(update) => self.status = Some(update.status),
+            _ => (),
+        }
+    }
+
+    fn confirm(&mut self, message: &ServerMessage) {
+        let checked = match message {
+            ServerMessage::Connected(connected) => &connected.checked_locations,
+            ServerMessage::RoomUpdate(update) => match &update.checked_locations {
+                Some(checked) => checked,
+                None => return,
+            },
+            _ => return,
+        };
+
+        for location in checked {
+            self.locations.remove(location);
+        }
+    }
+
+    fn resend(&self) -> Vec<ClientMessage> {
+        let mut messages = vec![];
+        if !self.locations.is_empty() {
+            messages.push(ClientMessage::LocationChecks(LocationChecks {
+                locations: self.locations.iter().copied().collect(),
+            }));
+        }
+        if let Some(status) = self.status {
+            messages.push(ClientMessage::StatusUpdate(StatusUpdate { status }));
+        }
+        messages
+    }
+}
+
+async fn supervisor(client: Client, info: ConnectInfo, backoff: Backoff, mut outbound: UnboundedReceiver<Outbound>, events: UnboundedSender<Event>, pending: Arc<Mutex<Pending>>) {
+    let mut unconfirmed = Unconfirmed::default();
+    let mut client = Some(client);
+
+    loop {
+        let client = match client.take() {
+            Some(client) => client,
+            None => match reconnect(&info, backoff, &events, &mut unconfirmed).await {
+                Some(client) => client,
+                None => {
+                    pending.lock().unwrap().close();
+                    return;
+                }
+            },
+        };
+
+        let keepalive = client.keepalive();
+        let (mut sender, mut receiver) = client.split();
+        receiver.set_idle_timeout(keepalive.map(|keepalive| keepalive.timeout));
+        let mut ping = Keepalive::interval(keepalive);
+
+        let mut resend = unconfirmed.resend();
+        let keys = pending.lock().unwrap().watched_keys();
+        if !keys.is_empty() {
+            resend.push(ClientMessage::SetNotify(SetNotify { keys }));
+        }
+
+        let mut lost = sender.send_batch(resend).await.is_err();
+
+        while !lost {
+            select! {
+                queued = outbound.recv() => match queued {
+                    Some(queued) => {
+                        // Requests from before the connection was lost already failed, the rest is sent
+                        let generation = pending.lock().unwrap().generation();
+                        let batch: Vec<ClientMessage> = once(queued)
+                            .chain(from_fn(|| outbound.try_recv().ok()))
+                            .filter(|queued| queued.generation.is_none_or(|queued| queued == generation))
+                            .flat_map(|queued| queued.messages)
+                            .collect();
+                        for message in &batch {
+                            unconfirmed.track(message);
+                        }
+                        lost = sender.send_batch(batch).await.is_err();
+                    }
+                    None => return,
+                },
+                message = receiver.recv() => match message {
+                    Ok(Some(message)) => {
+                        unconfirmed.confirm(&message);
+                        let event = pending.lock().unwrap().resolve(message);
+                        if event.is_some_and(|message| events.send(Event::Message(message)).is_err()) {
+                            return;
+                        }
+                    }
+                    Ok(None) => lost = true,
+                    Err(Error::ReplayFinished) => {
+                        pending.lock().unwrap().close();
+                        let _ = events.send(Event::Status(ConnectionStatus::Ended));
+                        return;
+                    }
+                    Err(err) => lost = err.is_connection_lost(),
+                },
+                _ = Keepalive::tick(&mut ping) => lost = sender.ping().await.is_err(),
+            }
+        }
+
+        pending.lock().unwrap().clear();
+    }
+}
+
+async fn reconnect(info: &ConnectInfo, backoff: Backoff, events: &UnboundedSender<Event>, unconfirmed: &mut Unconfirmed) -> Option<Client> {
+    let mut delay = backoff.initial;
+
+    for attempt in 1.. {
+        events.send(Event::Status(ConnectionStatus::Reconnecting { attempt })).ok()?;
+        sleep(delay).await;
+
+        match timeout(backoff.attempt_timeout, establish(info)).await {
+            Ok(Ok((client, connected, items))) => {
+                let connected = ServerMessage::Connected(connected);
+                unconfirmed.confirm(&connected);
+                events.send(Event::Message(connected)).ok()?;
+                events.send(Event::Message(ServerMessage::ReceivedItems(items))).ok()?;
+                events.send(Event::Status(ConnectionStatus::Connected)).ok()?;
+                return Some(client);
+            }
+            Ok(Err(Error::ConnectionRefused(reasons))) => {
+                let _ = events.send(Event::Status(ConnectionStatus::Refused(reasons)));
+                return None;
+            }
+            Ok(Err(Error::ReplayFinished)) => {
+                let _ = events.send(Event::Status(ConnectionStatus::Ended));
+                return None;
+            }
+            Ok(Err(_)) | Err(_) => (),
+        }
+
+        delay = (delay * backoff.factor).min(backoff.max);
+    }
+
+    None
+}
+
+async fn establish(info: &ConnectInfo) -> Result<(Client, Connected, ReceivedItems), Error> {
+    let (mut client, _) = Client::connect_with(&info.options).await?;
+    let connected = client.connect(&info.game, &info.name, &info.uuid, info.password.as_deref(), info.items_handling, &info.tags).await?;
+    let items = client.sync().await?;
+
+    Ok((client, connected, items))
+}
@@ -0,0 +1,194 @@
+use crate::Error;
+use futures_util::{Sink, Stream};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+#[cfg(feature = "native")]
+use {
+    crate::recording::{Direction, Recorder},
+    futures_util::ready,
+    tokio::net::TcpStream,
+    tokio_tungstenite::{MaybeTlsStream, WebSocketStream},
+    tungstenite::Message,
+};
+
+/**
+ * A frame of a connection. Archipelago only sends text, the rest is there for keepalives and closing
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Text(String),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close,
+}
+
+/**
+ * Anything that can carry frames to and from a server: a sink of frames to send plus a stream of the frames received.
+ *
+ * Implemented for every type that is both. Transports that can't ping, like a browser websocket, can drop pings, but
+ * should then be used without a keepalive. The stream ending or yielding `Frame::Close` ends the connection.
+ *
+ * Transports don't have to be `Send`, a browser websocket isn't, only `Client::dispatch` and `supervise` need one that
+ * is since they run the connection in a task of its own
+ */
+pub trait Transport: Stream<Item = Result<Frame, Error>> + Sink<Frame, Error = Error> + Unpin + 'static {}
+
+impl<T> Transport for T where T: Stream<Item = Result<Frame, Error>> + Sink<Frame, Error = Error> + Unpin + 'static {}
+
+/**
+ * The transport `ConnectOptions` opens, a websocket or a replay
+ */
+pub type BoxTransport = Box<dyn Transport + Send>;
+
+/**
+ * A websocket to a server
+ */
+#[cfg(feature = "native")]
+pub(crate) struct Websocket(pub(crate) WebSocketStream<MaybeTlsStream<TcpStream>>);
+
+#[cfg(feature = "native")]
+impl Stream for Websocket {
+    type Item = Result<Frame, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let frame = match ready!(Pin::new(&mut self.0).poll_next(cx)) {
+            Some(Ok(Message::Text(text))) => Frame::Text(text),
+            Some(Ok(Message::Ping(payload))) => Frame::Ping(payload),
+            Some(Ok(Message::Pong(payload))) => Frame::Pong(payload),
+            Some(Ok(Message::Close(_))) => Frame::Close,
+            Some(Ok(message)) => return Poll::Ready(Some(Err(Error::NonTextWebsocketResult(message)))),
+            Some(Err(err)) => return Poll::Ready(Some(Err(err.into()))),
+            None => return Poll::Ready(None),
+        };
+        Poll::Ready(Some(Ok(frame)))
+    }
+}
+
+#[cfg(feature = "native")]
+impl Sink<Frame> for Websocket {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.0).poll_ready(cx).map_err(Into::into)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, frame: Frame) -> Result<(), Error> {
+        let message = match frame {
+            Frame::Text(text) => Message::Text(text),
+            Frame::Ping(payload) => Message::Ping(payload),
+            Frame::Pong(payload) => Message::Pong(payload),
+            Frame::Close => Message::Close(None),
+        };
+        Ok(Pin::new(&mut self.0).start_send(message)?)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.0).poll_flush(cx).map_err(Into::into)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.0).poll_close(cx).map_err(Into::into)
+    }
+}
+
+/**
+ * Another transport with every frame going through a recorder
+ */
+#[cfg(feature = "native")]
+pub(crate) struct Recorded<T> {
+    pub(crate) inner: T,
+    pub(crate) recorder: Recorder,
+}
+
+#[cfg(feature = "native")]
+impl<T: Transport> Stream for Recorded<T> {
+    type Item = Result<Frame, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let next = Pin::new(&mut self.inner).poll_next(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &next {
+            self.recorder.record(Direction::In, frame);
+        }
+        next
+    }
+}
+
+#[cfg(feature = "native")]
+impl<T: Transport> Sink<Frame> for Recorded<T> {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, frame: Frame) -> Result<(), Error> {
+        self.recorder.record(Direction::Out, &frame);
+        Pin::new(&mut self.inner).start_send(frame)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/**
+ * One end of an in-memory connection, e.g. to play the server in tests. Frames sent on one end are received by the
+ * other, and dropping one end closes the connection for the other
+ */
+#[derive(Debug)]
+pub struct MemoryTransport {
+    sender: UnboundedSender<Frame>,
+    receiver: UnboundedReceiver<Frame>,
+}
+
+impl MemoryTransport {
+    pub fn pair() -> (MemoryTransport, MemoryTransport) {
+        let (client_sender, server_receiver) = unbounded_channel();
+        let (server_sender, client_receiver) = unbounded_channel();
+        (
+            MemoryTransport {
+                sender: client_sender,
+                receiver: client_receiver,
+            },
+            MemoryTransport {
+                sender: server_sender,
+                receiver: server_receiver,
+            },
+        )
+    }
+}
+
+impl Stream for MemoryTransport {
+    type Item = Result<Frame, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx).map(|frame| frame.map(Ok))
+    }
+}
+
+impl Sink<Frame> for MemoryTransport {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, frame: Frame) -> Result<(), Error> {
+        self.sender.send(frame).map_err(|_| Error::ConnectionClosed)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
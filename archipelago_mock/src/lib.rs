@@ -0,0 +1,593 @@
+use archipelago_client::{Client, ConnectInfo, ConnectOptions, Error, Frame, MemoryTransport, Transport};
+use archipelago_protocol::{
+    apply_operations, network_version, ClientMessage, Connect, Connected, ConnectionRefused, DataPackage, DataPackageObject, GameData, InvalidPacket, JSONMessagePart, LocationInfo, NetworkItem,
+    NetworkPlayer, NetworkSlot, PrintJSON, ReceivedItems, Retrieved, RoomInfo, RoomUpdate, ServerMessage, Set, SetReply, SlotData, SlotType,
+};
+use client_lib::data::{Environment, Hero, Location, TeamVillain, Variant, Villain};
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use serde_json::Value;
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    future::ready,
+    io,
+    sync::{Arc, Mutex},
+};
+use strum::IntoEnumIterator;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    select, spawn,
+    sync::broadcast::{self, error::RecvError},
+};
+use tokio_tungstenite::{accept_async, tungstenite::Message};
+
+pub const GAME: &str = "Sentinels of the Multiverse";
+
+/// The slot of the default `MockConfig`
+pub const PLAYER: &str = "Player";
+
+/// Highest location number generated for every SotM location in `sotm_game_data`
+pub const MAX_LOCATIONS_PER: u8 = 3;
+
+const ITEM_ID_BASE: i64 = 100_000;
+const LOCATION_ID_BASE: i64 = 200_000;
+const DIFFICULTIES: [&str; 4] = ["Normal", "Advanced", "Challenge", "Ultimate"];
+
+/// The room a `MockServer` pretends to host. The default is a passwordless single player SotM room
+#[derive(Debug, Clone)]
+pub struct MockConfig {
+    pub seed_name: String,
+    pub slot: String,
+    pub password: Option<String>,
+    pub slot_data: SlotData,
+    pub games: HashMap<String, GameData>,
+    pub items: Vec<NetworkItem>,
+    pub checked_locations: BTreeSet<i64>,
+    pub data_storage: HashMap<String, Value>,
+}
+
+impl Default for MockConfig {
+    fn default() -> Self {
+        MockConfig {
+            seed_name: String::from("mock"),
+            slot: String::from(PLAYER),
+            password: None,
+            slot_data: SlotData {
+                required_scions: 1,
+                required_villains: 1,
+                required_variants: 1,
+                villain_difficulty_points: [1, 2, 3, 4],
+                locations_per: [1; 6],
+            },
+            games: HashMap::from([(GAME.to_string(), sotm_game_data())]),
+            items: vec![],
+            checked_locations: BTreeSet::new(),
+            data_storage: HashMap::new(),
+        }
+    }
+}
+
+/// Datapackage for SotM using the same names as the real world, with stable ids
+pub fn sotm_game_data() -> GameData {
+    let mut item_names: Vec<String> = vec![];
+    item_names.extend(Villain::iter().map(|v| v.as_str().to_string()));
+    item_names.extend(TeamVillain::iter().map(|v| v.as_str().to_string()));
+    item_names.extend(Hero::iter().map(|h| h.as_str().to_string()));
+    item_names.extend(Environment::iter().map(|e| e.as_str().to_string()));
+    item_names.extend(Variant::iter().filter(|v| *v != Variant::Base).map(|v| v.as_str().to_string()));
+    item_names.push(String::from("Scion of Oblivaeon"));
+
+    let mut base_locations: Vec<String> = vec![];
+    for villain in Villain::iter().filter(|v| *v != Villain::SpiteAgentOfGloom && *v != Villain::SkinwalkerGloomweaver) {
+        base_locations.extend(DIFFICULTIES.iter().map(|d| format!("{} - {d}", villain.as_str())));
+    }
+    base_locations.extend(
+        [
+            "Spite: Agent of Gloom - Normal",
+            "Spite: Agent of Gloom - Advanced",
+            "Spite: Agent of Gloom and Skinwalker Gloomweaver - Challenge",
+            "Spite: Agent of Gloom and Skinwalker Gloomweaver - Ultimate",
+            "Skinwalker Gloomweaver - Normal",
+            "Skinwalker Gloomweaver - Advanced",
+        ]
+        .map(String::from),
+    );
+    for villain in TeamVillain::iter() {
+        base_locations.extend(DIFFICULTIES.iter().map(|d| format!("{} - {d}", villain.as_str())));
+    }
+    base_locations.extend(Environment::iter().map(|e| format!("{} - Any Difficulty", e.as_str())));
+    base_locations.extend(Variant::iter().filter(|v| !v.as_desc().is_empty()).map(|v| format!("{} - Unlock", v.as_str())));
+
+    let mut item_name_to_id = HashMap::new();
+    for (name, id) in item_names.into_iter().zip(ITEM_ID_BASE..) {
+        item_name_to_id.entry(name).or_insert(id);
+    }
+
+    let mut location_name_to_id = HashMap::new();
+    let location_names = (1..=MAX_LOCATIONS_PER).flat_map(|n| base_locations.iter().map(move |l| format!("{l} #{n}")));
+    for (name, id) in location_names.zip(LOCATION_ID_BASE..) {
+        location_name_to_id.insert(name, id);
+    }
+
+    let environments = || Environment::iter().map(|e| e.as_str().to_string());
+    let item_name_groups = HashMap::from([
+        (String::from("Villains"), Villain::iter().map(|v| v.as_str().to_string()).collect()),
+        (String::from("Environments"), environments().collect()),
+    ]);
+    let location_name_groups = HashMap::from([(
+        String::from("Environments"),
+        environments().flat_map(|e| (1..=MAX_LOCATIONS_PER).map(move |n| format!("{e} - Any Difficulty #{n}"))).collect(),
+    )]);
+
+    GameData {
+        item_name_to_id,
+        location_name_to_id,
+        item_name_groups: Some(item_name_groups),
+        location_name_groups: Some(location_name_groups),
+        ..GameData::default()
+    }
+}
+
+/// Id of a SotM item in `sotm_game_data`
+pub fn sotm_item_id(name: &str) -> Option<i64> {
+    sotm_game_data().item_name_to_id.get(name).copied()
+}
+
+/// Id of a SotM location in `sotm_game_data`, like `sotm_location_id("Baron Blade - Normal", 1)`
+pub fn sotm_location_id(name: &str, n: u8) -> Option<i64> {
+    sotm_game_data().location_name_to_id.get(&format!("{name} #{n}")).copied()
+}
+
+/// A SotM item as it would be sent to slot 1 by another player
+pub fn sotm_item(name: &str) -> NetworkItem {
+    NetworkItem {
+        item: sotm_item_id(name).unwrap_or_else(|| panic!("{name} is not a SotM item")),
+        location: 0,
+        player: 0,
+        flags: 0,
+    }
+}
+
+/// Connects an open client as `PLAYER`, asking for every item
+pub async fn connect_player<T: Transport>(client: &mut Client<T>) -> Result<Connected, Error> {
+    client.connect(GAME, PLAYER, "", None, Some(7), &["AP".into()]).await
+}
+
+/// A client connected to `server` as `PLAYER`
+pub async fn connected_client(server: &MockServer) -> Result<Client, Error> {
+    let (mut client, _) = Client::new(server.url()).await?;
+    connect_player(&mut client).await?;
+    Ok(client)
+}
+
+/// What `supervise` needs to connect as `PLAYER` again, like `connect_player` does
+pub fn connect_info_with(options: ConnectOptions) -> ConnectInfo {
+    ConnectInfo {
+        options,
+        game: GAME.to_string(),
+        name: PLAYER.to_string(),
+        uuid: String::new(),
+        password: None,
+        items_handling: Some(7),
+        tags: vec!["AP".into()],
+    }
+}
+
+/// What `supervise` needs to connect to `server` as `PLAYER` again
+pub fn connect_info(server: &MockServer) -> ConnectInfo {
+    connect_info_with(ConnectOptions::new(server.url()))
+}
+
+#[derive(Debug, Clone)]
+enum Push {
+    Messages(Vec<ServerMessage>),
+    /// A frame sent as is, e.g. one recorded from a real server
+    Frame(String),
+    /// Messages for only some connections, by id
+    Targeted(HashSet<usize>, Vec<ServerMessage>),
+    Stall,
+    Disconnect,
+}
+
+#[derive(Debug)]
+struct Room {
+    config: MockConfig,
+    /// Every frame received, as the messages in it
+    received: Vec<Vec<ClientMessage>>,
+    push: broadcast::Sender<Push>,
+    next_connection: usize,
+    /// Data storage keys each connection asked to be notified about with `SetNotify`
+    notify: HashMap<usize, HashSet<String>>,
+}
+
+/// A local websocket server speaking the Archipelago protocol, for tests.
+///
+/// The server answers `Connect`, `Sync`, `GetDataPackage`, `LocationChecks`, `LocationScouts` and `Say` like a real
+/// room would, emulates data storage with `Get`, `Set` and `SetNotify`, and records every message a client sends.
+/// Anything else is only recorded.
+pub struct MockServer {
+    url: String,
+    room: Arc<Mutex<Room>>,
+    push: broadcast::Sender<Push>,
+}
+
+impl MockServer {
+    pub async fn start(config: MockConfig) -> io::Result<MockServer> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("ws://{}", listener.local_addr()?);
+        let (push, _) = broadcast::channel(64);
+        let room = Arc::new(Mutex::new(Room {
+            config,
+            received: vec![],
+            push: push.clone(),
+            next_connection: 0,
+            notify: HashMap::new(),
+        }));
+
+        let accept_room = room.clone();
+        let accept_push = push.clone();
+        spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                spawn(handle(stream, accept_room.clone(), accept_push.subscribe()));
+            }
+        });
+
+        Ok(MockServer { url, room, push })
+    }
+
+    /// Connect to the room without a socket. The connection is served like any other, the returned end is for the client
+    #[allow(clippy::result_large_err)]
+    pub fn connect_in_memory(&self) -> MemoryTransport {
+        let (client, server) = MemoryTransport::pair();
+        let ws = server
+            .map(|frame| frame.map(frame_to_message))
+            .with(|message| ready(Ok::<_, archipelago_client::Error>(message_to_frame(message))));
+        spawn(serve_connection(ws, self.room.clone(), self.push.subscribe()));
+        client
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Every message received from any client so far, in order
+    pub fn received(&self) -> Vec<ClientMessage> {
+        self.room.lock().unwrap().received.concat()
+    }
+
+    /// Every message received so far, grouped by the frame it arrived in
+    pub fn received_frames(&self) -> Vec<Vec<ClientMessage>> {
+        self.room.lock().unwrap().received.clone()
+    }
+
+    pub fn checked_locations(&self) -> BTreeSet<i64> {
+        self.room.lock().unwrap().config.checked_locations.clone()
+    }
+
+    /// Current value of a data storage key
+    pub fn data_storage(&self, key: &str) -> Option<Value> {
+        self.room.lock().unwrap().config.data_storage.get(key).cloned()
+    }
+
+    /// Send messages to every connected client as a single frame
+    pub fn push(&self, messages: Vec<ServerMessage>) {
+        let _ = self.push.send(Push::Messages(messages));
+    }
+
+    /// Add items to the slot and send them to every connected client
+    pub fn give_items(&self, items: Vec<NetworkItem>) {
+        let mut room = self.room.lock().unwrap();
+        let index = room.config.items.len() as i32;
+        room.config.items.extend(items.iter().cloned());
+        let _ = self.push.send(Push::Messages(vec![ServerMessage::ReceivedItems(ReceivedItems { index, items })]));
+    }
+
+    /// Send a raw text frame to every open connection, without checking that it holds valid server messages
+    pub fn push_frame(&self, frame: impl Into<String>) {
+        let _ = self.push.send(Push::Frame(frame.into()));
+    }
+
+    /// Close every open connection. New connections are still accepted
+    pub fn disconnect(&self) {
+        let _ = self.push.send(Push::Disconnect);
+    }
+
+    /// Stop reading from and answering every open connection without closing them, like a server that went away
+    /// without a goodbye. Stalled connections are only closed by `disconnect`, new connections are served as usual
+    pub fn stall(&self) {
+        let _ = self.push.send(Push::Stall);
+    }
+}
+
+async fn handle(stream: TcpStream, room: Arc<Mutex<Room>>, push: broadcast::Receiver<Push>) {
+    if let Ok(ws) = accept_async(stream).await {
+        serve_connection(ws, room, push).await;
+    }
+}
+
+async fn serve_connection<S, E>(ws: S, room: Arc<Mutex<Room>>, push: broadcast::Receiver<Push>)
+where
+    S: Stream<Item = Result<Message, E>> + Sink<Message> + Unpin,
+{
+    let id = {
+        let mut room = room.lock().unwrap();
+        room.next_connection += 1;
+        room.next_connection
+    };
+    serve(ws, id, &room, push).await;
+    room.lock().unwrap().notify.remove(&id);
+}
+
+async fn serve<S, E>(mut ws: S, id: usize, room: &Mutex<Room>, mut push: broadcast::Receiver<Push>)
+where
+    S: Stream<Item = Result<Message, E>> + Sink<Message> + Unpin,
+{
+    let room_info = room.lock().unwrap().room_info();
+    if send(&mut ws, vec![ServerMessage::RoomInfo(room_info)]).await.is_err() {
+        return;
+    }
+
+    loop {
+        // Pushes first, so anything pushed before a client message arrives is also sent before the reply to it
+        select! {
+            biased;
+            pushed = push.recv() => match pushed {
+                Ok(Push::Messages(messages)) => {
+                    if send(&mut ws, messages).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(Push::Frame(frame)) => {
+                    if ws.send(Message::Text(frame)).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(Push::Targeted(targets, messages)) => {
+                    if targets.contains(&id) && send(&mut ws, messages).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(Push::Stall) => {
+                    while !matches!(push.recv().await, Ok(Push::Disconnect) | Err(RecvError::Closed)) {}
+                    return;
+                }
+                Ok(Push::Disconnect) | Err(RecvError::Closed) => {
+                    let _ = ws.send(Message::Close(None)).await;
+                    return;
+                }
+                Err(RecvError::Lagged(_)) => (),
+            },
+            frame = ws.next() => match frame {
+                Some(Ok(Message::Text(text))) => {
+                    let Ok(messages) = serde_json::from_str::<Vec<ClientMessage>>(&text) else {
+                        continue;
+                    };
+                    let replies: Vec<ServerMessage> = {
+                        let mut room = room.lock().unwrap();
+                        room.received.push(messages.clone());
+                        messages.into_iter().flat_map(|message| room.respond(id, message)).collect()
+                    };
+                    if !replies.is_empty() && send(&mut ws, replies).await.is_err() {
+                        return;
+                    }
+                }
+                Some(Ok(_)) => (),
+                Some(Err(_)) | None => return,
+            },
+        }
+    }
+}
+
+async fn send<S: Sink<Message> + Unpin>(ws: &mut S, messages: Vec<ServerMessage>) -> Result<(), S::Error> {
+    ws.send(Message::Text(serde_json::to_string(&messages).expect("server messages always serialize"))).await
+}
+
+fn frame_to_message(frame: Frame) -> Message {
+    match frame {
+        Frame::Text(text) => Message::Text(text),
+        Frame::Ping(payload) => Message::Ping(payload),
+        Frame::Pong(payload) => Message::Pong(payload),
+        Frame::Close => Message::Close(None),
+    }
+}
+
+/// The mock only sends text and closes, anything else is sent as a close as well
+fn message_to_frame(message: Message) -> Frame {
+    match message {
+        Message::Text(text) => Frame::Text(text),
+        Message::Ping(payload) => Frame::Ping(payload),
+        Message::Pong(payload) => Frame::Pong(payload),
+        Message::Close(_) | Message::Binary(_) | Message::Frame(_) => Frame::Close,
+    }
+}
+
+impl Room {
+    fn room_info(&self) -> RoomInfo {
+        RoomInfo {
+            version: network_version(),
+            tags: vec![],
+            password: self.config.password.is_some(),
+            permissions: HashMap::new(),
+            hint_cost: 10,
+            location_check_points: 1,
+            games: self.config.games.keys().cloned().collect(),
+            datapackage_checksums: self.config.games.iter().map(|(game, data)| (game.clone(), data.checksum())).collect(),
+            seed_name: self.config.seed_name.clone(),
+            time: 0.0,
+        }
+    }
+
+    fn respond(&mut self, id: usize, message: ClientMessage) -> Vec<ServerMessage> {
+        match message {
+            ClientMessage::Connect(connect) => self.connect(&connect),
+            ClientMessage::Sync => vec![self.received_items()],
+            ClientMessage::GetDataPackage(request) => {
+                let games = self
+                    .config
+                    .games
+                    .iter()
+                    .filter(|(game, _)| request.games.as_ref().is_none_or(|games| games.contains(game)))
+                    .map(|(game, data)| (game.clone(), data.clone()))
+                    .collect();
+                vec![ServerMessage::DataPackage(DataPackage { data: DataPackageObject { games } })]
+            }
+            ClientMessage::LocationChecks(checks) => {
+                let new: Vec<i64> = checks.locations.into_iter().filter(|location| self.config.checked_locations.insert(*location)).collect();
+                if new.is_empty() {
+                    vec![]
+                } else {
+                    vec![ServerMessage::RoomUpdate(RoomUpdate {
+                        checked_locations: Some(new),
+                        ..RoomUpdate::default()
+                    })]
+                }
+            }
+            ClientMessage::LocationScouts(scouts) => vec![ServerMessage::LocationInfo(LocationInfo {
+                locations: scouts
+                    .locations
+                    .into_iter()
+                    .map(|location| NetworkItem {
+                        item: 0,
+                        location,
+                        player: 1,
+                        flags: 0,
+                    })
+                    .collect(),
+            })],
+            ClientMessage::Say(say) => vec![ServerMessage::PrintJSON(PrintJSON {
+                data: vec![JSONMessagePart {
+                    r#type: None,
+                    text: Some(format!("{}: {}", self.config.slot, say.text)),
+                    color: None,
+                    flags: None,
+                    player: None,
+                }],
+                r#type: Some(String::from("Chat")),
+                receiving: None,
+                item: None,
+                found: None,
+                countdown: None,
+            })],
+            ClientMessage::Get(get) => {
+                let keys = get.keys.into_iter().map(|key| {
+                    let value = self.config.data_storage.get(&key).cloned().unwrap_or(Value::Null);
+                    (key, value)
+                });
+                vec![ServerMessage::Retrieved(Retrieved::new(keys.collect()))]
+            }
+            ClientMessage::Set(set) => self.set(id, set),
+            ClientMessage::SetNotify(notify) => {
+                self.notify.entry(id).or_default().extend(notify.keys);
+                vec![]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Apply a `Set`, replying to the sender if it wants a reply and notifying everyone watching the key
+    fn set(&mut self, id: usize, set: Set) -> Vec<ServerMessage> {
+        let original_value = self.config.data_storage.get(&set.key).cloned();
+        let value = match apply_operations(original_value.clone().unwrap_or(set.default), &set.operations) {
+            Ok(value) => value,
+            Err(err) => {
+                return vec![ServerMessage::InvalidPacket(InvalidPacket {
+                    r#type: String::from("arguments"),
+                    original_cmd: Some(String::from("Set")),
+                    text: err.to_string(),
+                })]
+            }
+        };
+        self.config.data_storage.insert(set.key.clone(), value.clone());
+
+        let reply = ServerMessage::SetReply(SetReply::new(set.key.clone(), value, original_value.unwrap_or(Value::Null)).with_extra(set.extra));
+        let watchers: HashSet<usize> = self
+            .notify
+            .iter()
+            .filter(|(watcher, keys)| **watcher != id && keys.contains(&set.key))
+            .map(|(watcher, _)| *watcher)
+            .collect();
+        if !watchers.is_empty() {
+            let _ = self.push.send(Push::Targeted(watchers, vec![reply.clone()]));
+        }
+
+        let watching = self.notify.get(&id).is_some_and(|keys| keys.contains(&set.key));
+        if set.want_reply || watching {
+            vec![reply]
+        } else {
+            vec![]
+        }
+    }
+
+    fn connect(&self, connect: &Connect) -> Vec<ServerMessage> {
+        let mut errors = vec![];
+        if connect.name != self.config.slot {
+            errors.push(String::from("InvalidSlot"));
+        }
+        if connect.game != GAME {
+            errors.push(String::from("InvalidGame"));
+        }
+        if self.config.password.is_some() && connect.password != self.config.password {
+            errors.push(String::from("InvalidPassword"));
+        }
+        if !errors.is_empty() {
+            return vec![ServerMessage::ConnectionRefused(ConnectionRefused { errors })];
+        }
+
+        let slot_locations = self.slot_locations();
+
+        vec![
+            ServerMessage::Connected(Connected {
+                team: 0,
+                slot: 1,
+                players: vec![NetworkPlayer {
+                    team: 0,
+                    slot: 1,
+                    alias: self.config.slot.clone(),
+                    name: self.config.slot.clone(),
+                }],
+                missing_locations: slot_locations.difference(&self.config.checked_locations).copied().collect(),
+                checked_locations: self.config.checked_locations.iter().copied().collect(),
+                slot_data: self.config.slot_data,
+                slot_info: HashMap::from([(
+                    String::from("1"),
+                    NetworkSlot {
+                        name: self.config.slot.clone(),
+                        game: GAME.to_string(),
+                        r#type: SlotType::Player,
+                        group_members: vec![],
+                    },
+                )]),
+            }),
+            self.received_items(),
+        ]
+    }
+
+    /// Locations that exist for the slot, based on `locations_per` in the slot data
+    fn slot_locations(&self) -> BTreeSet<i64> {
+        let Some(data) = self.config.games.get(GAME) else {
+            return BTreeSet::new();
+        };
+
+        data.location_name_to_id
+            .iter()
+            .filter(|(name, _)| {
+                Location::from_str(name).is_some_and(|(location, n)| {
+                    let per = self.config.slot_data.locations_per[match location {
+                        Location::Variant(_) => 5,
+                        Location::Villain((_, d)) | Location::TeamVillain((_, d)) => d as usize,
+                        Location::Environment(_) => 4,
+                        Location::Victory => return false,
+                    }];
+                    i16::from(n) <= i16::from(per)
+                })
+            })
+            .map(|(_, id)| *id)
+            .collect()
+    }
+
+    fn received_items(&self) -> ServerMessage {
+        ServerMessage::ReceivedItems(ReceivedItems {
+            index: 0,
+            items: self.config.items.clone(),
+        })
+    }
+}
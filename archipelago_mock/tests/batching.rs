@@ -0,0 +1,60 @@
+use archipelago_client::{supervise, Backoff, Event};
+use archipelago_mock::{connect_info, connected_client, sotm_location_id, MockConfig, MockServer};
+use archipelago_protocol::{ClientMessage, ClientStatus, LocationChecks, Say, ServerMessage, StatusUpdate};
+
+fn commands(frame: &[ClientMessage]) -> Vec<&'static str> {
+    frame
+        .iter()
+        .map(|message| match message {
+            ClientMessage::Say(_) => "Say",
+            ClientMessage::LocationChecks(_) => "LocationChecks",
+            ClientMessage::StatusUpdate(_) => "StatusUpdate",
+            _ => "other",
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn batches_are_sent_in_one_frame() {
+    let server = MockServer::start(MockConfig::default()).await.unwrap();
+    let mut client = connected_client(&server).await.unwrap();
+    let location = sotm_location_id("Baron Blade - Normal", 1).unwrap();
+
+    client.send_batch(vec![]).await.unwrap();
+    client
+        .send_batch(vec![
+            ClientMessage::LocationChecks(LocationChecks { locations: vec![location] }),
+            ClientMessage::StatusUpdate(StatusUpdate { status: ClientStatus::Goal }),
+            ClientMessage::Say(Say { text: String::from("gg") }),
+        ])
+        .await
+        .unwrap();
+    while !matches!(client.recv().await.unwrap(), Some(ServerMessage::PrintJSON(_))) {}
+
+    let frames = server.received_frames();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(commands(&frames[1]), ["LocationChecks", "StatusUpdate", "Say"]);
+    assert!(server.checked_locations().contains(&location));
+}
+
+#[tokio::test]
+async fn supervisor_coalesces_queued_messages() {
+    let server = MockServer::start(MockConfig::default()).await.unwrap();
+    let info = connect_info(&server);
+    let (sender, mut events) = supervise(connected_client(&server).await.unwrap(), info, Backoff::default());
+
+    // The supervisor only runs once this test yields, so all of these are queued by then
+    let location = sotm_location_id("Baron Blade - Normal", 1).unwrap();
+    sender.location_checks(vec![location]).unwrap();
+    sender
+        .send_batch(vec![
+            ClientMessage::StatusUpdate(StatusUpdate { status: ClientStatus::Goal }),
+            ClientMessage::Say(Say { text: String::from("gg") }),
+        ])
+        .unwrap();
+
+    while !matches!(events.recv().await, Some(Event::Message(ServerMessage::PrintJSON(_)))) {}
+
+    let frames = server.received_frames();
+    assert_eq!(commands(frames.last().unwrap()), ["LocationChecks", "StatusUpdate", "Say"]);
+}
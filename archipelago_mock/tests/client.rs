@@ -0,0 +1,352 @@
+use archipelago_client::{supervise, Backoff, Client, ConnectInfo, ConnectOptions, ConnectionStatus, Error, Event, Keepalive};
+use archipelago_mock::{connect_info, connect_info_with, connect_player, connected_client, sotm_item, sotm_item_id, sotm_location_id, MockConfig, MockServer, GAME};
+use archipelago_protocol::{ClientMessage, RefusalReason, RoomUpdate, ServerMessage};
+use client_lib::{
+    data::{Item, Location, Villain},
+    datapackage::{DatapackageStore, DefaultDatapackageStore},
+    test_support::MemoryStore,
+    ItemSync, LocationMerge, Session,
+};
+use serde_json::json;
+use std::{collections::HashMap, time::Duration};
+use tokio::net::TcpListener;
+use tokio_tungstenite::accept_async;
+
+fn quick_backoff() -> Backoff {
+    Backoff {
+        initial: Duration::from_millis(10),
+        max: Duration::from_millis(10),
+        factor: 1,
+        attempt_timeout: Duration::from_millis(200),
+    }
+}
+
+#[tokio::test]
+async fn connects_and_fetches_datapackage() {
+    let server = MockServer::start(MockConfig {
+        items: vec![sotm_item("Baron Blade")],
+        ..MockConfig::default()
+    })
+    .await
+    .unwrap();
+
+    let (mut client, room_info) = Client::new(server.url()).await.unwrap();
+    assert_eq!(room_info.seed_name, "mock");
+    assert!(room_info.datapackage_checksums.contains_key(GAME));
+
+    let data_package = client.get_data_package(Some([GAME.to_string()].into())).await.unwrap();
+    let game_data = &data_package.data.games[GAME];
+    assert_eq!(game_data.item_name_to_id.get("Baron Blade"), sotm_item_id("Baron Blade").as_ref());
+    assert!(game_data.location_name_to_id.keys().all(|name| Location::from_str(name).is_some()));
+
+    let connected = connect_player(&mut client).await.unwrap();
+    assert_eq!(connected.slot, 1);
+    assert!(connected.checked_locations.is_empty());
+
+    let items = client.sync().await.unwrap();
+    assert_eq!(items.index, 0);
+    assert_eq!(items.items.len(), 1);
+}
+
+#[tokio::test]
+async fn split_client_sends_and_receives() {
+    let server = MockServer::start(MockConfig::default()).await.unwrap();
+    let (mut sender, mut receiver) = connected_client(&server).await.unwrap().split();
+
+    // Connected is answered with the current items
+    assert!(matches!(receiver.recv().await.unwrap(), Some(ServerMessage::ReceivedItems(_))));
+
+    let location = sotm_location_id("Baron Blade - Normal", 1).unwrap();
+    sender.location_checks(vec![location]).await.unwrap();
+    match receiver.recv().await.unwrap() {
+        Some(ServerMessage::RoomUpdate(update)) => assert_eq!(update.checked_locations, Some(vec![location])),
+        other => panic!("expected RoomUpdate, got {other:?}"),
+    }
+
+    sender.say("hello").await.unwrap();
+    assert!(matches!(receiver.recv().await.unwrap(), Some(ServerMessage::PrintJSON(_))));
+
+    assert!(server.checked_locations().contains(&location));
+    assert!(matches!(server.received().last(), Some(ClientMessage::Say(say)) if say.text == "hello"));
+}
+
+#[tokio::test]
+async fn session_reconciles_with_server() {
+    let checked = sotm_location_id("Baron Blade - Normal", 1).unwrap();
+    let server = MockServer::start(MockConfig {
+        items: vec![sotm_item("Baron Blade"), sotm_item("Scion of Oblivaeon")],
+        checked_locations: [checked].into(),
+        ..MockConfig::default()
+    })
+    .await
+    .unwrap();
+
+    let (mut client, _) = Client::new(server.url()).await.unwrap();
+    let data_package = client.get_data_package(None).await.unwrap();
+    let connected = connect_player(&mut client).await.unwrap();
+    let items = client.sync().await.unwrap();
+
+    let mut datapackage_store = DefaultDatapackageStore::new(HashMap::new());
+    datapackage_store.cache(data_package.data);
+    datapackage_store.build_player_map(&connected);
+
+    let mut session: Session<_, MemoryStore> = Session::new("mock", datapackage_store, connected, "Player");
+    assert!(!session.state.checked_locations.has_unchecked_villain(Villain::BaronBlade, 0));
+
+    let item_ids: Vec<i64> = items.items.iter().map(|item| item.item).collect();
+    assert_eq!(session.receive_items(items.index, &item_ids), ItemSync::Applied);
+    assert_eq!(session.receive_items(items.index, &item_ids), ItemSync::Applied);
+    assert_eq!(session.state.items.scions, 1);
+    assert!(session.state.items.has_villain(Villain::BaronBlade));
+
+    assert_eq!(session.receive_items(5, &[sotm_item_id("Scion of Oblivaeon").unwrap()]), ItemSync::Gap);
+    assert_eq!(session.state.items.scions, 1);
+    assert_eq!(session.datapackage_store.id_to_own_item(item_ids[1]), Some(Item::Scion));
+}
+
+async fn mock_session(server: &MockServer) -> Session<DefaultDatapackageStore, MemoryStore> {
+    let (mut client, _) = Client::new(server.url()).await.unwrap();
+    let data_package = client.get_data_package(None).await.unwrap();
+    let connected = connect_player(&mut client).await.unwrap();
+
+    let mut datapackage_store = DefaultDatapackageStore::new(HashMap::new());
+    datapackage_store.cache(data_package.data);
+    datapackage_store.build_player_map(&connected);
+    Session::new("mock", datapackage_store, connected, "Player")
+}
+
+#[tokio::test]
+async fn sent_locations_are_marked() {
+    let server = MockServer::start(MockConfig::default()).await.unwrap();
+    let mut session = mock_session(&server).await;
+
+    let sent = session.send_locations(&[Location::Villain((Villain::BaronBlade, 0)), Location::Victory]);
+    assert_eq!(sent, [sotm_location_id("Baron Blade - Normal", 1).unwrap()]);
+    assert!(!session.state.checked_locations.has_unchecked_villain(Villain::BaronBlade, 0));
+    assert!(!session.state.checked_locations.victory);
+}
+
+#[tokio::test]
+async fn missing_locations_keep_unconfirmed_checks() {
+    let server = MockServer::start(MockConfig::default()).await.unwrap();
+    let mut session = mock_session(&server).await;
+    let location = sotm_location_id("Baron Blade - Normal", 1).unwrap();
+    session.send_locations(&[Location::Villain((Villain::BaronBlade, 0))]);
+
+    // Like the Connected after a reconnect, before the check was sent again
+    session.update_locations(&[], &[location]);
+    assert!(!session.state.checked_locations.has_unchecked_villain(Villain::BaronBlade, 0));
+
+    // Once confirmed, the server decides again
+    session.update_locations(&[location], &[]);
+    session.update_locations(&[], &[location]);
+    assert!(session.state.checked_locations.has_unchecked_villain(Villain::BaronBlade, 0));
+}
+
+#[tokio::test]
+async fn checks_are_saved_once_confirmed() {
+    let server = MockServer::start(MockConfig::default()).await.unwrap();
+    let mut session = mock_session(&server).await;
+    let location = sotm_location_id("Baron Blade - Normal", 1).unwrap();
+    let saved_unchecked = |session: &Session<_, MemoryStore>| session.persistent_store.last_save().unwrap().locations.has_unchecked_villain(Villain::BaronBlade, 0);
+
+    session.send_locations(&[Location::Villain((Villain::BaronBlade, 0))]);
+    session.save();
+    assert!(saved_unchecked(&session));
+
+    assert!(session.update_locations(&[location], &[]));
+    session.save();
+    assert!(!saved_unchecked(&session));
+    assert!(!session.update_locations(&[location], &[]));
+}
+
+#[tokio::test]
+async fn merges_tell_what_changed() {
+    let server = MockServer::start(MockConfig::default()).await.unwrap();
+    let mut session = mock_session(&server).await;
+    let mut remote = session.state.checked_locations;
+    remote.mark_villain(Villain::BaronBlade, 0);
+
+    // Only catching up, so there's nothing to share back
+    assert_eq!(session.merge_locations(&remote), LocationMerge { gained: true, ahead: false });
+    assert_eq!(session.merge_locations(&remote), LocationMerge { gained: false, ahead: false });
+
+    session.state.checked_locations.mark_villain(Villain::Omnitron, 0);
+    assert_eq!(session.merge_locations(&remote), LocationMerge { gained: false, ahead: true });
+}
+
+#[tokio::test]
+async fn supervisor_reconnects_and_resends() {
+    let server = MockServer::start(MockConfig::default()).await.unwrap();
+    let info = connect_info(&server);
+    let (sender, mut events) = supervise(connected_client(&server).await.unwrap(), info, quick_backoff());
+
+    server.disconnect();
+    while !matches!(events.recv().await, Some(Event::Status(ConnectionStatus::Reconnecting { .. }))) {}
+
+    // Queued while reconnecting and sent once the connection is back
+    let location = sotm_location_id("Ambuscade - Normal", 1).unwrap();
+    sender.location_checks(vec![location]).unwrap();
+
+    let mut reconnected = false;
+    while let Some(event) = events.recv().await {
+        match event {
+            Event::Status(ConnectionStatus::Connected) => reconnected = true,
+            Event::Message(ServerMessage::RoomUpdate(update)) if update.checked_locations == Some(vec![location]) => break,
+            _ => (),
+        }
+    }
+
+    assert!(reconnected);
+    assert!(server.checked_locations().contains(&location));
+}
+
+#[tokio::test]
+async fn dispatcher_routes_replies() {
+    let server = MockServer::start(MockConfig::default()).await.unwrap();
+    let (dispatcher, mut events) = connected_client(&server).await.unwrap().dispatch();
+
+    let location = sotm_location_id("Baron Blade - Normal", 1).unwrap();
+    let (scouted, data_package) = tokio::join!(dispatcher.location_scouts(vec![location], 0), dispatcher.get_data_package(None));
+    assert_eq!(scouted.unwrap().locations[0].location, location);
+    assert!(data_package.unwrap().data.games.contains_key(GAME));
+
+    dispatcher.say("hello").await.unwrap();
+    assert!(matches!(events.recv().await, Some(ServerMessage::ReceivedItems(_))));
+    assert!(matches!(events.recv().await, Some(ServerMessage::PrintJSON(_))));
+
+    server.disconnect();
+    assert!(events.recv().await.is_none());
+    assert!(dispatcher.get_data_package(None).await.is_err());
+}
+
+#[tokio::test]
+async fn unknown_messages_do_not_drop_the_frame() {
+    let server = MockServer::start(MockConfig::default()).await.unwrap();
+    let (_sender, mut receiver) = connected_client(&server).await.unwrap().split();
+    assert!(matches!(receiver.recv().await.unwrap(), Some(ServerMessage::ReceivedItems(_))));
+
+    let unknown = json!({"cmd": "FutureCommand", "data": [1, 2]});
+    server.push(vec![
+        serde_json::from_value(unknown.clone()).unwrap(),
+        ServerMessage::RoomUpdate(RoomUpdate {
+            hint_points: Some(5),
+            ..RoomUpdate::default()
+        }),
+    ]);
+
+    assert!(matches!(receiver.recv().await.unwrap(), Some(ServerMessage::Unknown { cmd, raw }) if cmd == "FutureCommand" && raw == unknown));
+    assert!(matches!(receiver.recv().await.unwrap(), Some(ServerMessage::RoomUpdate(update)) if update.hint_points == Some(5)));
+}
+
+#[tokio::test]
+async fn malformed_known_messages_are_errors() {
+    let server = MockServer::start(MockConfig::default()).await.unwrap();
+    let (_sender, mut receiver) = connected_client(&server).await.unwrap().split();
+    assert!(matches!(receiver.recv().await.unwrap(), Some(ServerMessage::ReceivedItems(_))));
+
+    let malformed = json!({"cmd": "ReceivedItems", "index": 0, "items": "not a list"});
+    server.push(vec![serde_json::from_value(malformed.clone()).unwrap()]);
+
+    assert!(matches!(receiver.recv().await, Err(Error::MalformedMessage { cmd, raw }) if cmd == "ReceivedItems" && raw == malformed));
+}
+
+#[tokio::test]
+async fn connection_refusals_are_typed() {
+    let server = MockServer::start(MockConfig {
+        password: Some(String::from("secret")),
+        ..MockConfig::default()
+    })
+    .await
+    .unwrap();
+
+    let (mut client, room_info) = Client::new(server.url()).await.unwrap();
+    assert!(room_info.password);
+    match client.connect(GAME, "Nobody", "", Some("wrong"), Some(7), &[]).await {
+        Err(Error::ConnectionRefused(reasons)) => assert_eq!(reasons, [RefusalReason::InvalidSlot, RefusalReason::InvalidPassword]),
+        other => panic!("expected ConnectionRefused, got {other:?}"),
+    }
+
+    assert!(client.connect(GAME, "Player", "", Some("secret"), Some(7), &[]).await.is_ok());
+}
+
+#[tokio::test]
+async fn supervisor_detects_stalled_connections() {
+    let server = MockServer::start(MockConfig::default()).await.unwrap();
+    let keepalive = Keepalive {
+        interval: Duration::from_millis(20),
+        timeout: Duration::from_millis(100),
+    };
+    let options = ConnectOptions::new(server.url()).keepalive(Some(keepalive));
+    let info = connect_info_with(options.clone());
+
+    let (mut client, _) = Client::connect_with(&options).await.unwrap();
+    connect_player(&mut client).await.unwrap();
+    let (_sender, mut events) = supervise(client, info, quick_backoff());
+
+    // Pings keep a healthy but quiet connection alive
+    let quiet = tokio::time::timeout(Duration::from_millis(300), async {
+        while let Some(event) = events.recv().await {
+            if let Event::Status(status) = event {
+                return status;
+            }
+        }
+        unreachable!()
+    })
+    .await;
+    assert!(quiet.is_err());
+
+    server.stall();
+    let lost = tokio::time::timeout(Duration::from_secs(2), async {
+        while !matches!(events.recv().await, Some(Event::Status(ConnectionStatus::Reconnecting { .. }))) {}
+        while !matches!(events.recv().await, Some(Event::Status(ConnectionStatus::Connected))) {}
+    })
+    .await;
+    assert!(lost.is_ok());
+}
+
+#[tokio::test]
+async fn supervisor_stops_when_refused() {
+    let server = MockServer::start(MockConfig::default()).await.unwrap();
+    // The slot is gone by the time the supervisor reconnects
+    let info = ConnectInfo {
+        name: String::from("Nobody"),
+        ..connect_info(&server)
+    };
+    let (sender, mut events) = supervise(connected_client(&server).await.unwrap(), info, quick_backoff());
+
+    server.disconnect();
+    let mut statuses = vec![];
+    while let Some(event) = events.recv().await {
+        if let Event::Status(status) = event {
+            statuses.push(status);
+        }
+    }
+    assert_eq!(statuses, [ConnectionStatus::Reconnecting { attempt: 1 }, ConnectionStatus::Refused(vec![RefusalReason::InvalidSlot])]);
+    assert!(matches!(sender.get(vec![]).await, Err(Error::ConnectionClosed)));
+}
+
+#[tokio::test]
+async fn reconnect_attempts_time_out() {
+    let server = MockServer::start(MockConfig::default()).await.unwrap();
+
+    // Accepts the websocket, then never says anything
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let silent = format!("ws://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        let mut open = vec![];
+        while let Ok((stream, _)) = listener.accept().await {
+            open.push(accept_async(stream).await);
+        }
+    });
+
+    let info = connect_info_with(ConnectOptions::new(silent).handshake_timeout(None));
+    let (_sender, mut events) = supervise(connected_client(&server).await.unwrap(), info, quick_backoff());
+
+    server.disconnect();
+    let retried = tokio::time::timeout(Duration::from_secs(2), async {
+        while !matches!(events.recv().await, Some(Event::Status(ConnectionStatus::Reconnecting { attempt: 2 }))) {}
+    })
+    .await;
+    assert!(retried.is_ok());
+}
@@ -0,0 +1,151 @@
+use archipelago_client::{Client, Frame, MemoryTransport};
+use archipelago_mock::{connected_client, MockConfig, MockServer};
+use archipelago_protocol::{apply_operations, ClientMessage, DataStorageError, DataStorageOperation, ServerMessage, Set, SetNotify};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+
+fn apply(value: Value, operations: Vec<DataStorageOperation>) -> Result<Value, DataStorageError> {
+    apply_operations(value, &operations)
+}
+
+#[test]
+fn operations_round_trip_through_json() {
+    let operations = vec![DataStorageOperation::Add(json!(1)), DataStorageOperation::Floor, DataStorageOperation::LeftShift(json!(2))];
+    let encoded = serde_json::to_value(&operations).unwrap();
+    assert_eq!(
+        encoded,
+        json!([{"operation": "add", "value": 1}, {"operation": "floor", "value": null}, {"operation": "left_shift", "value": 2}])
+    );
+    assert_eq!(serde_json::from_value::<Vec<DataStorageOperation>>(encoded).unwrap(), operations);
+    assert!(serde_json::from_value::<DataStorageOperation>(json!({"operation": "frobnicate", "value": 1})).is_err());
+}
+
+#[test]
+fn numeric_operations_follow_python() {
+    use DataStorageOperation::*;
+
+    assert_eq!(apply(json!(5), vec![Add(json!(2)), Mul(json!(3)), Pow(json!(2))]), Ok(json!(441)));
+    assert_eq!(apply(json!(-7), vec![Mod(json!(3))]), Ok(json!(2)));
+    assert_eq!(apply(json!(7), vec![Mod(json!(-3))]), Ok(json!(-2)));
+    assert_eq!(apply(json!(2), vec![Pow(json!(-1))]), Ok(json!(0.5)));
+    assert_eq!(apply(json!(1.5), vec![Floor]), Ok(json!(1)));
+    assert_eq!(apply(json!(-1.5), vec![Ceil]), Ok(json!(-1)));
+    assert_eq!(apply(json!(3), vec![Max(json!(5)), Min(json!(4))]), Ok(json!(4)));
+    assert_eq!(apply(json!(0b1100), vec![And(json!(0b1010)), Or(json!(1)), Xor(json!(0b11))]), Ok(json!(0b1010)));
+    assert_eq!(apply(json!(1), vec![LeftShift(json!(4)), RightShift(json!(2))]), Ok(json!(4)));
+    assert_eq!(apply(json!(1), vec![Mod(json!(0))]), Err(DataStorageError::DivisionByZero));
+    assert_eq!(apply(json!(i64::MAX), vec![Add(json!(1))]), Err(DataStorageError::Overflow));
+}
+
+#[test]
+fn container_operations_follow_python() {
+    use DataStorageOperation::*;
+
+    assert_eq!(apply(json!([1, 2]), vec![Add(json!([3, 2])), Remove(json!(2))]), Ok(json!([1, 3, 2])));
+    assert_eq!(apply(json!([1, 2, 3]), vec![Pop(json!(-1)), Pop(json!(5))]), Ok(json!([1, 2])));
+    assert_eq!(apply(json!({"a": 1, "b": 2}), vec![Update(json!({"b": 3, "c": 4})), Pop(json!("a"))]), Ok(json!({"b": 3, "c": 4})));
+    assert_eq!(apply(json!("ab"), vec![Mul(json!(2))]), Ok(json!("abab")));
+    assert_eq!(apply(json!({"a": 1}), vec![Default, Replace(json!(null))]), Ok(json!(null)));
+    assert!(matches!(
+        apply(json!([1]), vec![Update(json!({}))]),
+        Err(DataStorageError::UnsupportedOperands { operation: "update", .. })
+    ));
+}
+
+#[tokio::test]
+async fn mock_server_emulates_data_storage() {
+    let server = MockServer::start(MockConfig::default()).await.unwrap();
+
+    let mut watcher = connected_client(&server).await.unwrap();
+    assert!(matches!(watcher.recv().await.unwrap(), Some(ServerMessage::ReceivedItems(_))));
+    watcher.send(ClientMessage::SetNotify(SetNotify { keys: vec![String::from("counter")] })).await.unwrap();
+    // Answered after the SetNotify, so the watcher is subscribed from here on
+    watcher.get(vec![]).await.unwrap();
+
+    let mut client = connected_client(&server).await.unwrap();
+    assert!(matches!(client.recv().await.unwrap(), Some(ServerMessage::ReceivedItems(_))));
+    let reply = client
+        .set(String::from("counter"), json!(10), true, vec![DataStorageOperation::Default, DataStorageOperation::Add(json!(5))])
+        .await
+        .unwrap();
+    assert_eq!(reply.value_as::<i32>().unwrap(), 15);
+    assert_eq!(reply.original_value_as::<Option<i32>>().unwrap(), None);
+    assert_eq!(server.data_storage("counter"), Some(json!(15)));
+
+    let retrieved = client.get(vec![String::from("counter"), String::from("missing")]).await.unwrap();
+    assert_eq!(retrieved.get_as::<i32>("counter").unwrap(), 15);
+    assert_eq!(retrieved.get("missing"), Some(&Value::Null));
+    assert!(retrieved.get_as::<String>("counter").is_err());
+
+    match watcher.recv().await.unwrap() {
+        Some(ServerMessage::SetReply(reply)) => assert_eq!(reply.key(), "counter"),
+        other => panic!("expected SetReply, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn dispatcher_subscriptions_receive_updates() {
+    let server = MockServer::start(MockConfig::default()).await.unwrap();
+
+    let client = connected_client(&server).await.unwrap();
+    let (dispatcher, _events) = client.dispatch();
+    let mut updates = dispatcher.subscribe(vec![String::from("hints")]).await.unwrap();
+
+    let mut other = connected_client(&server).await.unwrap();
+    // Make sure the subscription is in place before writing
+    dispatcher.get(vec![]).await.unwrap();
+    other
+        .send(ClientMessage::Set(Set {
+            key: String::from("hints"),
+            default: json!([]),
+            want_reply: false,
+            operations: vec![DataStorageOperation::Add(json!(["Baron Blade"]))],
+            extra: serde_json::Map::new(),
+        }))
+        .await
+        .unwrap();
+
+    let update = updates.recv().await.unwrap();
+    assert_eq!(update.key(), "hints");
+    assert_eq!(update.value_as::<Vec<String>>().unwrap(), ["Baron Blade"]);
+
+    server.disconnect();
+    assert!(updates.recv().await.is_none());
+}
+
+#[tokio::test]
+async fn set_waits_for_its_own_reply() {
+    let (client, mut server) = MemoryTransport::pair();
+    let room_info = json!([{
+        "cmd": "RoomInfo", "version": {"major": 0, "minor": 5, "build": 0, "class": "Version"}, "tags": [], "password": false, "permissions": {},
+        "hint_cost": 10, "location_check_points": 1, "games": [], "datapackage_checksums": {}, "seed_name": "scripted", "time": 0.0
+    }]);
+    server.send(Frame::Text(room_info.to_string())).await.unwrap();
+
+    let (client, _) = Client::with_transport(client).await.unwrap();
+    let (dispatcher, _events) = client.dispatch();
+    let mut updates = dispatcher.subscribe(vec![String::from("counter")]).await.unwrap();
+    let set = tokio::spawn(async move { dispatcher.set(String::from("counter"), json!(0), vec![DataStorageOperation::Add(json!(1))]).await });
+
+    let mut set_frame = None;
+    while set_frame.is_none() {
+        let Some(Ok(Frame::Text(text))) = server.next().await else {
+            panic!("expected a text frame");
+        };
+        let message = serde_json::from_str::<Value>(&text).unwrap()[0].clone();
+        set_frame = (message["cmd"] == "Set").then_some(message);
+    }
+    let mut reply = set_frame.unwrap();
+    reply["cmd"] = json!("SetReply");
+    reply["value"] = json!(11);
+    reply["original_value"] = json!(10);
+
+    // Another client's change to the key comes first and must not be taken for the reply
+    let other = json!([{"cmd": "SetReply", "key": "counter", "value": 10, "original_value": 0}]);
+    server.send(Frame::Text(other.to_string())).await.unwrap();
+    server.send(Frame::Text(json!([reply]).to_string())).await.unwrap();
+
+    assert_eq!(set.await.unwrap().unwrap().value(), &json!(11));
+    assert_eq!(updates.recv().await.unwrap().value(), &json!(10));
+    assert_eq!(updates.recv().await.unwrap().value(), &json!(11));
+}
@@ -0,0 +1,163 @@
+use archipelago_client::Client;
+use archipelago_mock::{connect_player, sotm_game_data, sotm_item_id, sotm_location_id, GAME};
+use archipelago_mock::{MockConfig, MockServer};
+use archipelago_protocol::{DataPackageObject, GameData, JSONMessagePart, PrintJSON, ServerMessage};
+use client_lib::datapackage::{CachedDatapackageStore, DatapackageBackend, DatapackageStore, FilesystemBackend, MemoryBackend};
+use serde_json::json;
+use std::fs;
+
+#[test]
+fn checksum_matches_the_server() {
+    // As computed by Archipelago: sha1 of the JSON with sorted keys, no whitespace and non-ASCII left as is
+    let data: GameData = serde_json::from_value(json!({
+        "item_name_to_id": {"Baron Blade": 1, "Café": 2},
+        "location_name_to_id": {"Start": 10},
+        "item_name_groups": {"Villains": ["Baron Blade"]},
+        "original_id_range": [1, 2],
+        "checksum": "5019d144ebcb24c34b48621fa716b8c542e07e8f"
+    }))
+    .unwrap();
+    assert_eq!(data.checksum(), "5019d144ebcb24c34b48621fa716b8c542e07e8f");
+}
+
+#[test]
+fn groups_are_only_sent_back_if_received() {
+    let without: GameData = serde_json::from_value(json!({"item_name_to_id": {}, "location_name_to_id": {}})).unwrap();
+    assert_eq!(serde_json::to_value(&without).unwrap(), json!({"item_name_to_id": {}, "location_name_to_id": {}}));
+
+    let with: GameData = serde_json::from_value(json!({"item_name_to_id": {}, "location_name_to_id": {}, "item_name_groups": {}})).unwrap();
+    assert_eq!(serde_json::to_value(&with).unwrap()["item_name_groups"], json!({}));
+}
+
+#[test]
+fn name_groups() {
+    let data = sotm_game_data();
+    let mut store = CachedDatapackageStore::with_backend(MemoryBackend::default(), [(GAME.to_string(), data.checksum())].into());
+    assert!(store.item_groups(GAME).is_none());
+
+    store.cache(DataPackageObject {
+        games: [(GAME.to_string(), data.clone())].into(),
+    });
+    assert!(store.item_groups(GAME).unwrap()["Villains"].contains(&String::from("Baron Blade")));
+    assert!(store.location_groups(GAME).unwrap()["Environments"].contains(&String::from("Megalopolis - Any Difficulty #1")));
+    assert!(store.location_groups(GAME).unwrap().get("Villains").is_none());
+
+    // Still verified with the groups included
+    let store = CachedDatapackageStore::with_backend(store.backend().clone(), [(GAME.to_string(), data.checksum())].into());
+    assert!(store.missing_games().is_empty());
+    assert_eq!(store.item_groups(GAME), data.item_name_groups.as_ref());
+}
+
+#[test]
+fn cache_is_verified() {
+    let dir = std::env::temp_dir().join(format!("archipelago-datapackage-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join(GAME)).unwrap();
+
+    let data = sotm_game_data();
+    let checksum = data.checksum();
+    let requested = || [(GAME.to_string(), checksum.clone())].into();
+    let path = dir.join(GAME).join(&checksum);
+    let open = || CachedDatapackageStore::with_backend(FilesystemBackend::new(&dir), requested());
+
+    // A truncated cache file is refetched rather than trusted
+    fs::write(&path, &serde_json::to_string(&data).unwrap()[..100]).unwrap();
+    let mut store = open();
+    assert_eq!(&*store.missing_games(), [GAME.to_string()]);
+
+    store.cache(DataPackageObject {
+        games: [(GAME.to_string(), data.clone())].into(),
+    });
+    assert!(store.missing_games().is_empty());
+    assert!(!path.with_extension("tmp").exists());
+    let store = open();
+    assert!(store.missing_games().is_empty());
+    assert!(store.id_to_own_item(sotm_item_id("Baron Blade").unwrap()).is_some());
+
+    // Parses fine, but isn't what the checksum says
+    let mut tampered = data.clone();
+    tampered.item_name_to_id.insert(String::from("Not an item"), -1);
+    fs::write(&path, serde_json::to_string(&tampered).unwrap()).unwrap();
+    let mut store = open();
+    assert_eq!(store.missing_games().len(), 1);
+
+    // Data the server sent that doesn't match either is used, but not cached
+    fs::remove_file(&path).unwrap();
+    store.cache(DataPackageObject {
+        games: [(GAME.to_string(), tampered)].into(),
+    });
+    assert!(!path.exists());
+    assert!(store.id_to_own_item(sotm_item_id("Baron Blade").unwrap()).is_some());
+
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[test]
+fn memory_backend() {
+    let data = sotm_game_data();
+    let baron_blade = sotm_item_id("Baron Blade").unwrap();
+    let requested = || [(GAME.to_string(), data.checksum()), (String::from("Other"), String::from("unknown"))].into();
+
+    let mut store = CachedDatapackageStore::with_backend(MemoryBackend::default(), requested());
+    let mut missing = store.missing_games().to_vec();
+    missing.sort();
+    assert_eq!(missing, [String::from("Other"), GAME.to_string()]);
+    assert_eq!(store.game_item(GAME, baron_blade), None);
+
+    store.cache(DataPackageObject {
+        games: [(GAME.to_string(), data.clone())].into(),
+    });
+    assert_eq!(&*store.missing_games(), [String::from("Other")]);
+    assert_eq!(store.game_item(GAME, baron_blade), Some("Baron Blade"));
+
+    // A second session with the same backend finds it cached
+    let backend = store.backend().clone();
+    assert!(backend.load(GAME, &data.checksum()).is_some());
+    assert!(backend.load("Other", "unknown").is_none());
+    let store = CachedDatapackageStore::with_backend(backend, requested());
+    assert_eq!(&*store.missing_games(), [String::from("Other")]);
+    assert!(store.id_to_own_item(baron_blade).is_some());
+}
+
+fn part(r#type: &str, text: i64, player: i32) -> JSONMessagePart {
+    JSONMessagePart {
+        r#type: Some(r#type.to_string()),
+        text: Some(text.to_string()),
+        color: None,
+        flags: None,
+        player: Some(player),
+    }
+}
+
+#[tokio::test]
+async fn missing_games_are_fetched_mid_session() {
+    let server = MockServer::start(MockConfig::default()).await.unwrap();
+    let (mut client, _) = Client::new(server.url()).await.unwrap();
+    let connected = connect_player(&mut client).await.unwrap();
+    assert!(matches!(client.recv().await.unwrap(), Some(ServerMessage::ReceivedItems(_))));
+
+    // As if the game's data couldn't be fetched when connecting
+    let mut store = CachedDatapackageStore::with_backend(MemoryBackend::default(), Default::default());
+    store.build_player_map(&connected);
+
+    let item = sotm_item_id("Baron Blade").unwrap();
+    let location = sotm_location_id("Baron Blade - Normal", 1).unwrap();
+    let msg = PrintJSON {
+        data: vec![part("item_id", item, 1), part("location_id", location, 1), part("player_id", 1, 1)],
+        r#type: Some(String::from("ItemSend")),
+        receiving: Some(1),
+        item: None,
+        found: None,
+        countdown: None,
+    };
+    assert_eq!(store.get_item(1, item), "Unknown item");
+    let misses = store.report_misses(&msg);
+    assert_eq!(misses, [GAME.to_string()]);
+    assert!(store.report_misses(&msg).is_empty());
+
+    let data_package = client.get_data_package(Some(misses.into())).await.unwrap();
+    store.cache(data_package.data);
+    assert_eq!(store.get_item(1, item), "Baron Blade");
+    assert_eq!(store.get_location(1, location), "Baron Blade - Normal #1");
+    assert!(store.report_misses(&msg).is_empty());
+}
@@ -0,0 +1,107 @@
+use archipelago_client::Client;
+use archipelago_mock::{connect_player, connected_client, MockConfig, MockServer};
+use archipelago_protocol::{PrintJSON, ServerMessage};
+
+/// Frames as a real server sends them, with fields the client doesn't know about
+const CHAT_FRAME: &str = r#"[
+    {"cmd": "PrintJSON", "data": [{"text": "Player: first"}], "type": "Chat", "team": 0, "slot": 1, "message": "first"},
+    {"cmd": "PrintJSON", "data": [{"text": "Player: second"}], "type": "Chat", "team": 0, "slot": 1, "message": "second"}
+]"#;
+const ITEMS_FRAME: &str = r#"[
+    {"cmd": "PrintJSON", "data": [{"text": "before items"}], "type": "Tutorial"},
+    {"cmd": "ReceivedItems", "index": 0, "items": [{"item": 42, "location": 7, "player": 2, "flags": 1, "class": "NetworkItem"}]},
+    {"cmd": "PrintJSON", "data": [{"text": "after items"}], "type": "Tutorial"}
+]"#;
+const ROOM_UPDATE_FRAME: &str = r#"[{"cmd": "RoomUpdate", "hint_points": 3}]"#;
+
+/// A connected client past the items that answer `Connect`
+async fn synced_client(server: &MockServer) -> Client {
+    let mut client = connected_client(server).await.unwrap();
+    // Connected is answered with the current items
+    assert!(matches!(client.recv().await.unwrap(), Some(ServerMessage::ReceivedItems(_))));
+    client
+}
+
+fn text(message: Option<ServerMessage>) -> String {
+    match message {
+        Some(ServerMessage::PrintJSON(PrintJSON { data, .. })) => data.into_iter().filter_map(|part| part.text).collect(),
+        other => panic!("expected PrintJSON, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn skipped_messages_come_back_in_server_order() {
+    let server = MockServer::start(MockConfig::default()).await.unwrap();
+    let mut client = synced_client(&server).await;
+
+    server.push_frame(CHAT_FRAME);
+    server.push_frame(ROOM_UPDATE_FRAME);
+    client.sync().await.unwrap();
+
+    assert_eq!(text(client.recv().await.unwrap()), "Player: first");
+    assert_eq!(text(client.recv().await.unwrap()), "Player: second");
+    assert!(matches!(client.recv().await.unwrap(), Some(ServerMessage::RoomUpdate(update)) if update.hint_points == Some(3)));
+}
+
+#[tokio::test]
+async fn replies_in_the_middle_of_a_frame() {
+    let server = MockServer::start(MockConfig::default()).await.unwrap();
+    let mut client = synced_client(&server).await;
+
+    // The recorded ReceivedItems answers the sync, the one the mock sends for it stays queued after the rest
+    server.push_frame(ITEMS_FRAME);
+    let items = client.sync().await.unwrap();
+    assert_eq!(items.items[0].item, 42);
+
+    assert_eq!(text(client.recv().await.unwrap()), "before items");
+    assert_eq!(text(client.recv().await.unwrap()), "after items");
+    assert!(matches!(client.recv().await.unwrap(), Some(ServerMessage::ReceivedItems(items)) if items.items.is_empty()));
+}
+
+#[tokio::test]
+async fn buffered_messages_are_searched_before_the_network() {
+    let server = MockServer::start(MockConfig::default()).await.unwrap();
+    let mut client = synced_client(&server).await;
+
+    // Reading the first line leaves the rest of the frame buffered, ahead of the answer to the say
+    server.push_frame(ITEMS_FRAME);
+    client.say("hello").await.unwrap();
+    assert_eq!(text(client.recv().await.unwrap()), "before items");
+
+    let items = client.sync().await.unwrap();
+    assert_eq!(items.items[0].item, 42);
+    assert_eq!(text(client.recv().await.unwrap()), "after items");
+    assert_eq!(text(client.recv().await.unwrap()), "Player: hello");
+    assert!(matches!(client.recv().await.unwrap(), Some(ServerMessage::ReceivedItems(_))));
+}
+
+#[tokio::test]
+async fn split_receiver_keeps_frame_order() {
+    let server = MockServer::start(MockConfig::default()).await.unwrap();
+    let (_sender, mut receiver) = synced_client(&server).await.split();
+
+    server.push_frame(CHAT_FRAME);
+    server.push_frame(ITEMS_FRAME);
+
+    assert_eq!(text(receiver.recv().await.unwrap()), "Player: first");
+    assert_eq!(text(receiver.recv().await.unwrap()), "Player: second");
+    assert_eq!(text(receiver.recv().await.unwrap()), "before items");
+    assert!(matches!(receiver.recv().await.unwrap(), Some(ServerMessage::ReceivedItems(_))));
+    assert_eq!(text(receiver.recv().await.unwrap()), "after items");
+}
+
+#[tokio::test]
+async fn connect_and_datapackage_skip_other_messages() {
+    let server = MockServer::start(MockConfig::default()).await.unwrap();
+    let (mut client, _) = Client::new(server.url()).await.unwrap();
+
+    server.push_frame(CHAT_FRAME);
+    client.get_data_package(None).await.unwrap();
+    server.push_frame(ROOM_UPDATE_FRAME);
+    connect_player(&mut client).await.unwrap();
+
+    assert_eq!(text(client.recv().await.unwrap()), "Player: first");
+    assert_eq!(text(client.recv().await.unwrap()), "Player: second");
+    assert!(matches!(client.recv().await.unwrap(), Some(ServerMessage::RoomUpdate(_))));
+    assert!(matches!(client.recv().await.unwrap(), Some(ServerMessage::ReceivedItems(_))));
+}
@@ -0,0 +1,57 @@
+use archipelago_mock::{connected_client, MockConfig, MockServer};
+use archipelago_protocol::{ClientMessage, ServerMessage};
+use client_lib::{
+    data::Villain,
+    persistent::{decode_value, encode, DataStoragePersistentStore, PersistentStore, SaveKey, SlotSave},
+    test_support::{collect_sets, MemoryStore},
+};
+
+#[tokio::test]
+async fn saves_are_shared_through_data_storage() {
+    let server = MockServer::start(MockConfig::default()).await.unwrap();
+    let key = SaveKey {
+        seed: String::from("seed"),
+        team: 0,
+        slot: 1,
+    };
+    let mut save = SlotSave {
+        items_index: 12,
+        multi_send: true,
+        ..SlotSave::default()
+    };
+    save.locations.mark_villain(Villain::BaronBlade, 3);
+
+    let mut watcher = connected_client(&server).await.unwrap();
+    assert!(matches!(watcher.recv().await.unwrap(), Some(ServerMessage::ReceivedItems(_))));
+    watcher.set_notify(vec![key.storage_key()]).await.unwrap();
+    // Answered after the SetNotify, so the watcher is subscribed from here on
+    watcher.get(vec![]).await.unwrap();
+
+    let mut client = connected_client(&server).await.unwrap();
+    let mut store = DataStoragePersistentStore::<MemoryStore>::new(&key);
+    let sets = collect_sets(&mut store);
+    store.save(&save);
+    let set = sets.lock().unwrap().pop().unwrap();
+    client.send(ClientMessage::Set(set)).await.unwrap();
+
+    let reply = loop {
+        match watcher.recv().await.unwrap() {
+            Some(ServerMessage::SetReply(reply)) => break reply,
+            Some(_) => continue,
+            None => panic!("connection closed before the SetReply"),
+        }
+    };
+    assert_eq!(reply.key(), key.storage_key());
+    let mut elsewhere = DataStoragePersistentStore::<MemoryStore>::new(&key);
+    assert_eq!(encode(&elsewhere.receive(reply.value()).unwrap()), encode(&save));
+
+    // Nothing to share back when it only catches up
+    let echoed = collect_sets(&mut elsewhere);
+    elsewhere.save_local(&save);
+    assert!(echoed.lock().unwrap().is_empty());
+    assert_eq!(encode(&elsewhere.load()), encode(&save));
+
+    let retrieved = watcher.get(vec![key.storage_key()]).await.unwrap();
+    assert_eq!(encode(&decode_value(retrieved.get(&key.storage_key()).unwrap()).unwrap()), encode(&save));
+    assert_eq!(server.data_storage(&key.storage_key()).as_ref(), retrieved.get(&key.storage_key()));
+}
@@ -0,0 +1,194 @@
+use archipelago_client::{supervise, Backoff, Client, ConnectOptions, ConnectionStatus, Direction, Error, Event, RecordedFrame, RecordedMessage};
+use archipelago_mock::{connect_info_with, connect_player, sotm_item, MockConfig, MockServer, GAME};
+use archipelago_protocol::{DataStorageOperation, ServerMessage};
+use serde_json::json;
+use std::{
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+/// A recording file for a test, removed again when dropped, also when the test fails
+struct TempRecording(PathBuf);
+
+impl Deref for TempRecording {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempRecording {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+fn recording_path(name: &str) -> TempRecording {
+    let path = std::env::temp_dir().join(format!("archipelago-{}-{name}.jsonl", std::process::id()));
+    let _ = fs::remove_file(&path);
+    TempRecording(path)
+}
+
+fn read_recording(path: &Path) -> Vec<RecordedFrame> {
+    fs::read_to_string(path).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+}
+
+async fn record_session(path: &Path) {
+    let server = MockServer::start(MockConfig {
+        items: vec![sotm_item("Baron Blade")],
+        ..MockConfig::default()
+    })
+    .await
+    .unwrap();
+
+    let (mut client, _) = Client::connect_with(&ConnectOptions::new(server.url()).record(Some(path.to_path_buf()))).await.unwrap();
+    connect_player(&mut client).await.unwrap();
+    client.say("hello").await.unwrap();
+    assert!(matches!(client.recv().await.unwrap(), Some(ServerMessage::ReceivedItems(_))));
+    assert!(matches!(client.recv().await.unwrap(), Some(ServerMessage::PrintJSON(_))));
+}
+
+#[tokio::test]
+async fn records_both_directions() {
+    let path = recording_path("both-directions");
+    record_session(&path).await;
+
+    let frames = read_recording(&path);
+    let texts: Vec<_> = frames
+        .iter()
+        .map(|frame| match &frame.message {
+            RecordedMessage::Text { text } => (frame.direction, serde_json::from_str::<serde_json::Value>(text).unwrap()[0]["cmd"].as_str().unwrap().to_string()),
+            other => panic!("expected only text frames, got {other:?}"),
+        })
+        .collect();
+    assert_eq!(
+        texts,
+        [
+            (Direction::In, String::from("RoomInfo")),
+            (Direction::Out, String::from("Connect")),
+            // Followed by ReceivedItems in the same frame
+            (Direction::In, String::from("Connected")),
+            (Direction::Out, String::from("Say")),
+            (Direction::In, String::from("PrintJSON")),
+        ]
+    );
+    assert!(frames.windows(2).all(|pair| pair[0].time <= pair[1].time));
+}
+
+#[tokio::test]
+async fn passwords_are_not_recorded() {
+    let path = recording_path("password");
+    let server = MockServer::start(MockConfig {
+        password: Some(String::from("secret")),
+        ..MockConfig::default()
+    })
+    .await
+    .unwrap();
+
+    let (mut client, _) = Client::connect_with(&ConnectOptions::new(server.url()).record(Some(path.to_path_buf()))).await.unwrap();
+    client.connect(GAME, "Player", "", Some("secret"), Some(7), &["AP".into()]).await.unwrap();
+
+    let recording = fs::read_to_string(&*path).unwrap();
+    assert!(!recording.contains("secret"));
+    let connect = read_recording(&path)
+        .into_iter()
+        .find_map(|frame| match frame.message {
+            RecordedMessage::Text { text } if frame.direction == Direction::Out => Some(serde_json::from_str::<serde_json::Value>(&text).unwrap()[0].clone()),
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(connect["cmd"], "Connect");
+    assert_eq!(connect["password"], "");
+}
+
+#[tokio::test]
+async fn replays_a_recording_without_a_server() {
+    let path = recording_path("replay");
+    record_session(&path).await;
+
+    let options = ConnectOptions::new("nowhere").replay(Some(path.to_path_buf()));
+    let (mut client, room_info) = Client::connect_with(&options).await.unwrap();
+    assert_eq!(room_info.seed_name, "mock");
+
+    let connected = client.connect(GAME, "Someone else", "", None, Some(7), &[]).await.unwrap();
+    assert_eq!(connected.slot, 1);
+    let items = client.sync().await.unwrap();
+    assert_eq!(items.items.len(), 1);
+    assert!(matches!(client.recv().await.unwrap(), Some(ServerMessage::PrintJSON(_))));
+
+    // Once the recording is over the replay ends instead of going quiet
+    assert!(matches!(client.recv().await, Err(Error::ReplayFinished)));
+}
+
+#[tokio::test]
+async fn replay_answers_what_the_client_asks() {
+    let path = recording_path("requests");
+    let server = MockServer::start(MockConfig::default()).await.unwrap();
+    let (mut client, _) = Client::connect_with(&ConnectOptions::new(server.url()).record(Some(path.to_path_buf()))).await.unwrap();
+    client.get_data_package(None).await.unwrap();
+    connect_player(&mut client).await.unwrap();
+    client.set(String::from("key"), json!(0), true, vec![DataStorageOperation::Replace(json!(5))]).await.unwrap();
+
+    // Without a datapackage request this time, and with a different tag on the set
+    let (mut client, _) = Client::connect_with(&ConnectOptions::new("nowhere").replay(Some(path.to_path_buf()))).await.unwrap();
+    let connected = connect_player(&mut client).await.unwrap();
+    assert_eq!(connected.slot, 1);
+    let reply = client.set(String::from("key"), json!(0), true, vec![]).await.unwrap();
+    assert_eq!(reply.value(), &json!(5));
+
+    // A request that was never recorded fails instead of waiting forever
+    let (mut client, _) = Client::connect_with(&ConnectOptions::new("nowhere").replay(Some(path.to_path_buf()))).await.unwrap();
+    client.get_data_package(None).await.unwrap();
+    assert!(matches!(client.location_scouts(vec![1], 0).await, Err(Error::InvalidRecording(_))));
+}
+
+#[tokio::test]
+async fn replay_ends_with_the_recording() {
+    let path = recording_path("closed");
+    let server = MockServer::start(MockConfig::default()).await.unwrap();
+    let (mut client, _) = Client::connect_with(&ConnectOptions::new(server.url()).record(Some(path.to_path_buf()))).await.unwrap();
+    server.disconnect();
+    assert!(matches!(client.recv().await, Err(Error::ConnectionClosed)));
+    assert_eq!(read_recording(&path).last().unwrap().message, RecordedMessage::Close);
+
+    let (mut client, _) = Client::connect_with(&ConnectOptions::new("nowhere").replay(Some(path.to_path_buf()))).await.unwrap();
+    assert!(matches!(client.recv().await, Err(Error::ReplayFinished)));
+}
+
+#[tokio::test]
+async fn supervised_replay_is_not_played_again() {
+    let path = recording_path("supervised");
+    record_session(&path).await;
+
+    let options = ConnectOptions::new("nowhere").replay(Some(path.to_path_buf()));
+    let (mut client, _) = Client::connect_with(&options).await.unwrap();
+    connect_player(&mut client).await.unwrap();
+    let info = connect_info_with(options);
+    let (sender, mut events) = supervise(client, info, Backoff::default());
+
+    let mut messages = 0;
+    loop {
+        match events.recv().await.unwrap() {
+            Event::Message(_) => messages += 1,
+            Event::Status(status) => {
+                assert_eq!(status, ConnectionStatus::Ended);
+                break;
+            }
+        }
+    }
+    // The items after Connected and the answer to the say, once
+    assert_eq!(messages, 2);
+    assert!(events.recv().await.is_none());
+    assert!(matches!(sender.get(vec![String::from("key")]).await, Err(Error::ConnectionClosed)));
+}
+
+#[tokio::test]
+async fn invalid_recordings_are_rejected() {
+    let path = recording_path("invalid");
+    fs::write(&*path, "{\"time\": 0, \"direction\": \"in\", \"kind\": \"ping\"}\nnot json\n").unwrap();
+
+    let options = ConnectOptions::new("nowhere").replay(Some(path.to_path_buf()));
+    assert!(matches!(Client::connect_with(&options).await, Err(Error::InvalidRecording(reason)) if reason.starts_with("line 2")));
+}
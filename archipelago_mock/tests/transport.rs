@@ -0,0 +1,136 @@
+use archipelago_client::{Client, Error, Frame, MemoryTransport};
+use archipelago_mock::{connect_player, sotm_item, sotm_location_id, MockConfig, MockServer};
+use archipelago_protocol::ServerMessage;
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use serde_json::json;
+use std::{
+    marker::PhantomData,
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll},
+};
+
+#[tokio::test]
+async fn client_helpers_work_in_memory() {
+    let server = MockServer::start(MockConfig {
+        items: vec![sotm_item("Baron Blade")],
+        data_storage: [(String::from("counter"), json!(1))].into(),
+        ..MockConfig::default()
+    })
+    .await
+    .unwrap();
+
+    let (mut client, room_info) = Client::with_transport(server.connect_in_memory()).await.unwrap();
+    assert_eq!(room_info.seed_name, "mock");
+    assert_eq!(client.url(), "");
+
+    connect_player(&mut client).await.unwrap();
+    assert_eq!(client.sync().await.unwrap().items.len(), 1);
+    assert_eq!(client.get(vec![String::from("counter")]).await.unwrap().get("counter"), Some(&json!(1)));
+
+    let location = sotm_location_id("Baron Blade - Normal", 1).unwrap();
+    let scouted = client.location_scouts(vec![location], 0).await.unwrap();
+    assert_eq!(scouted.locations[0].location, location);
+}
+
+#[tokio::test]
+async fn dispatcher_works_in_memory() {
+    let server = MockServer::start(MockConfig::default()).await.unwrap();
+    let (mut client, _) = Client::with_transport(server.connect_in_memory()).await.unwrap();
+    connect_player(&mut client).await.unwrap();
+
+    let (dispatcher, mut events) = client.dispatch();
+    assert!(matches!(events.recv().await, Some(ServerMessage::ReceivedItems(_))));
+    dispatcher.say("hello").await.unwrap();
+    assert!(matches!(events.recv().await, Some(ServerMessage::PrintJSON(_))));
+
+    server.disconnect();
+    assert!(events.recv().await.is_none());
+}
+
+/// Like a browser websocket, which can't leave its thread
+struct LocalTransport {
+    inner: MemoryTransport,
+    _not_send: PhantomData<Rc<()>>,
+}
+
+impl Stream for LocalTransport {
+    type Item = Result<Frame, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner).poll_next(cx)
+    }
+}
+
+impl Sink<Frame> for LocalTransport {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, frame: Frame) -> Result<(), Error> {
+        Pin::new(&mut self.inner).start_send(frame)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[tokio::test]
+async fn transports_do_not_have_to_be_send() {
+    let server = MockServer::start(MockConfig::default()).await.unwrap();
+    let transport = LocalTransport {
+        inner: server.connect_in_memory(),
+        _not_send: PhantomData,
+    };
+
+    let (mut client, _) = Client::with_transport(transport).await.unwrap();
+    // Nor `Debug`
+    assert!(format!("{client:?}").starts_with("Client {"));
+    connect_player(&mut client).await.unwrap();
+    client.say("hello").await.unwrap();
+    assert!(matches!(client.recv().await.unwrap(), Some(ServerMessage::ReceivedItems(_))));
+    assert!(matches!(client.recv().await.unwrap(), Some(ServerMessage::PrintJSON(_))));
+}
+
+#[tokio::test]
+async fn scripted_server() {
+    let (client, mut server) = MemoryTransport::pair();
+
+    let script = tokio::spawn(async move {
+        let room_info = json!([{
+            "cmd": "RoomInfo",
+            "version": {"major": 0, "minor": 5, "build": 0, "class": "Version"},
+            "generator_version": {"major": 0, "minor": 5, "build": 0, "class": "Version"},
+            "tags": [],
+            "password": false,
+            "permissions": {},
+            "hint_cost": 10,
+            "location_check_points": 1,
+            "games": [],
+            "datapackage_checksums": {},
+            "seed_name": "scripted",
+            "time": 0.0
+        }]);
+        server.send(Frame::Text(room_info.to_string())).await.unwrap();
+
+        // Answer the first say, then hang up
+        let Some(Ok(Frame::Text(text))) = server.next().await else {
+            panic!("expected a text frame");
+        };
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&text).unwrap()[0]["cmd"], "Say");
+        server.send(Frame::Close).await.unwrap();
+    });
+
+    let (mut client, room_info) = Client::with_transport(client).await.unwrap();
+    assert_eq!(room_info.seed_name, "scripted");
+    client.say("hello").await.unwrap();
+    assert!(matches!(client.recv().await, Err(Error::ConnectionClosed)));
+    script.await.unwrap();
+}
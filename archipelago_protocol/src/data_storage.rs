@@ -0,0 +1,261 @@
+use crate::DataStorageOperation;
+use serde_json::{Number, Value};
+use std::{cmp::Ordering, fmt};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DataStorageError {
+    /// The operation is not defined for the stored value and operand, the server rejects these as well
+    UnsupportedOperands {
+        operation: &'static str,
+        value: Value,
+        operand: Value,
+    },
+    DivisionByZero,
+    /// The result does not fit in a 64 bit integer or finite float
+    Overflow,
+}
+
+impl fmt::Display for DataStorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DataStorageError::UnsupportedOperands { operation, value, operand } => write!(f, "unsupported operands for {operation}: {value} and {operand}"),
+            DataStorageError::DivisionByZero => write!(f, "division by zero"),
+            DataStorageError::Overflow => write!(f, "numeric overflow"),
+        }
+    }
+}
+
+impl std::error::Error for DataStorageError {}
+
+/// Apply `operations` in order the same way the server does for a `Set`.
+///
+/// `value` is the currently stored value, or the `default` of the `Set` if the key has no value yet.
+/// Numbers follow Python semantics, so integer operands stay integers where Python would keep them as ints.
+pub fn apply_operations(value: Value, operations: &[DataStorageOperation]) -> Result<Value, DataStorageError> {
+    operations.iter().try_fold(value, apply)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Num {
+    Int(i64),
+    Float(f64),
+}
+
+impl Num {
+    fn from_value(value: &Value) -> Option<Num> {
+        match value {
+            Value::Number(number) => number.as_i64().map(Num::Int).or_else(|| number.as_f64().map(Num::Float)),
+            Value::Bool(bool) => Some(Num::Int(*bool as i64)),
+            _ => None,
+        }
+    }
+
+    fn as_f64(self) -> f64 {
+        match self {
+            Num::Int(int) => int as f64,
+            Num::Float(float) => float,
+        }
+    }
+
+    fn into_value(self) -> Result<Value, DataStorageError> {
+        match self {
+            Num::Int(int) => Ok(int.into()),
+            Num::Float(float) => Number::from_f64(float).map(Value::Number).ok_or(DataStorageError::Overflow),
+        }
+    }
+
+    fn partial_cmp(self, other: Num) -> Option<Ordering> {
+        match (self, other) {
+            (Num::Int(lhs), Num::Int(rhs)) => Some(lhs.cmp(&rhs)),
+            (lhs, rhs) => lhs.as_f64().partial_cmp(&rhs.as_f64()),
+        }
+    }
+}
+
+fn arithmetic(lhs: Num, rhs: Num, int: impl Fn(i64, i64) -> Result<Num, DataStorageError>, float: impl Fn(f64, f64) -> f64) -> Result<Value, DataStorageError> {
+    match (lhs, rhs) {
+        (Num::Int(lhs), Num::Int(rhs)) => int(lhs, rhs)?.into_value(),
+        (lhs, rhs) => Num::Float(float(lhs.as_f64(), rhs.as_f64())).into_value(),
+    }
+}
+
+fn checked(result: Option<i64>) -> Result<Num, DataStorageError> {
+    result.map(Num::Int).ok_or(DataStorageError::Overflow)
+}
+
+fn python_mod(lhs: Num, rhs: Num) -> Result<Value, DataStorageError> {
+    if rhs.as_f64() == 0.0 {
+        return Err(DataStorageError::DivisionByZero);
+    }
+    // The result takes the sign of the divisor
+    arithmetic(
+        lhs,
+        rhs,
+        |lhs, rhs| {
+            let rem = lhs.wrapping_rem(rhs);
+            Ok(Num::Int(if rem != 0 && (rem < 0) != (rhs < 0) { rem + rhs } else { rem }))
+        },
+        |lhs, rhs| {
+            let rem = lhs % rhs;
+            if rem != 0.0 && (rem < 0.0) != (rhs < 0.0) {
+                rem + rhs
+            } else {
+                rem
+            }
+        },
+    )
+}
+
+fn python_pow(lhs: Num, rhs: Num) -> Result<Value, DataStorageError> {
+    match (lhs, rhs) {
+        (Num::Int(lhs), Num::Int(rhs)) if rhs >= 0 => checked(u32::try_from(rhs).ok().and_then(|rhs| lhs.checked_pow(rhs)))?.into_value(),
+        (lhs, _) if lhs.as_f64() == 0.0 && rhs.as_f64() < 0.0 => Err(DataStorageError::DivisionByZero),
+        (lhs, rhs) => Num::Float(lhs.as_f64().powf(rhs.as_f64())).into_value(),
+    }
+}
+
+fn bitwise(operation: &'static str, value: &Value, operand: &Value, op: impl Fn(i64, i64) -> Result<i64, DataStorageError>) -> Result<Value, DataStorageError> {
+    match (Num::from_value(value), Num::from_value(operand)) {
+        (Some(Num::Int(lhs)), Some(Num::Int(rhs))) => Ok(op(lhs, rhs)?.into()),
+        _ => Err(unsupported(operation, value, operand)),
+    }
+}
+
+fn shift_amount(rhs: i64) -> Result<u32, DataStorageError> {
+    u32::try_from(rhs).map_err(|_| DataStorageError::Overflow)
+}
+
+fn unsupported(operation: &'static str, value: &Value, operand: &Value) -> DataStorageError {
+    DataStorageError::UnsupportedOperands {
+        operation,
+        value: value.clone(),
+        operand: operand.clone(),
+    }
+}
+
+fn repeat<T: Clone>(items: &[T], times: i64) -> Vec<T> {
+    let times = usize::try_from(times).unwrap_or(0);
+    items.iter().cloned().cycle().take(items.len() * times).collect()
+}
+
+fn apply(value: Value, operation: &DataStorageOperation) -> Result<Value, DataStorageError> {
+    use DataStorageOperation::*;
+
+    let name = operation.name();
+    let operand = match operation {
+        Replace(operand) => return Ok(operand.clone()),
+        Default => return Ok(value),
+        Floor | Ceil => &Value::Null,
+        Add(operand) | Mul(operand) | Pow(operand) | Mod(operand) | Max(operand) | Min(operand) | And(operand) | Or(operand) | Xor(operand) | LeftShift(operand) | RightShift(operand)
+        | Remove(operand) | Pop(operand) | Update(operand) => operand,
+    };
+    let numbers = Num::from_value(&value).zip(Num::from_value(operand));
+
+    match operation {
+        Replace(_) | Default => unreachable!(),
+        Add(_) => match (value, operand) {
+            (Value::Array(mut lhs), Value::Array(rhs)) => {
+                lhs.extend(rhs.iter().cloned());
+                Ok(Value::Array(lhs))
+            }
+            (Value::String(lhs), Value::String(rhs)) => Ok(Value::String(lhs + rhs)),
+            (value, operand) => match numbers {
+                Some((lhs, rhs)) => arithmetic(lhs, rhs, |lhs, rhs| checked(lhs.checked_add(rhs)), |lhs, rhs| lhs + rhs),
+                None => Err(unsupported(name, &value, operand)),
+            },
+        },
+        Mul(_) => match (&value, Num::from_value(operand)) {
+            (Value::Array(items), Some(Num::Int(times))) => Ok(Value::Array(repeat(items, times))),
+            (Value::String(text), Some(Num::Int(times))) => Ok(Value::String(text.repeat(usize::try_from(times).unwrap_or(0)))),
+            _ => match numbers {
+                Some((lhs, rhs)) => arithmetic(lhs, rhs, |lhs, rhs| checked(lhs.checked_mul(rhs)), |lhs, rhs| lhs * rhs),
+                None => Err(unsupported(name, &value, operand)),
+            },
+        },
+        Pow(_) => match numbers {
+            Some((lhs, rhs)) => python_pow(lhs, rhs),
+            None => Err(unsupported(name, &value, operand)),
+        },
+        Mod(_) => match numbers {
+            Some((lhs, rhs)) => python_mod(lhs, rhs),
+            None => Err(unsupported(name, &value, operand)),
+        },
+        Floor | Ceil => match Num::from_value(&value) {
+            Some(Num::Int(int)) => Ok(int.into()),
+            Some(Num::Float(float)) => {
+                let rounded = if matches!(operation, Floor) { float.floor() } else { float.ceil() };
+                // Python returns an int here, which can't hold infinities either
+                if rounded.is_finite() && rounded >= i64::MIN as f64 && rounded < i64::MAX as f64 {
+                    Ok((rounded as i64).into())
+                } else {
+                    Err(DataStorageError::Overflow)
+                }
+            }
+            None => Err(unsupported(name, &value, operand)),
+        },
+        Max(_) | Min(_) => {
+            let ordering = match (&value, operand) {
+                (Value::String(lhs), Value::String(rhs)) => Some(lhs.cmp(rhs)),
+                _ => match numbers {
+                    Some((lhs, rhs)) => lhs.partial_cmp(rhs),
+                    None => return Err(unsupported(name, &value, operand)),
+                },
+            };
+            // Like Python, the first argument wins unless the second is strictly better
+            let replace = match operation {
+                Max(_) => ordering == Some(Ordering::Less),
+                _ => ordering == Some(Ordering::Greater),
+            };
+            Ok(if replace { operand.clone() } else { value })
+        }
+        And(_) => bitwise(name, &value, operand, |lhs, rhs| Ok(lhs & rhs)),
+        Or(_) => bitwise(name, &value, operand, |lhs, rhs| Ok(lhs | rhs)),
+        Xor(_) => bitwise(name, &value, operand, |lhs, rhs| Ok(lhs ^ rhs)),
+        LeftShift(_) => bitwise(name, &value, operand, |lhs, rhs| {
+            let shift = shift_amount(rhs)?;
+            if lhs == 0 {
+                return Ok(0);
+            }
+            1i64.checked_shl(shift)
+                .filter(|_| shift < 63)
+                .and_then(|factor| lhs.checked_mul(factor))
+                .ok_or(DataStorageError::Overflow)
+        }),
+        RightShift(_) => bitwise(name, &value, operand, |lhs, rhs| Ok(lhs >> shift_amount(rhs)?.min(63))),
+        Remove(_) => match value {
+            Value::Array(mut items) => {
+                if let Some(index) = items.iter().position(|item| item == operand) {
+                    items.remove(index);
+                }
+                Ok(Value::Array(items))
+            }
+            value => Err(unsupported(name, &value, operand)),
+        },
+        // Popping an index or key that doesn't exist leaves the value untouched
+        Pop(_) => match (value, operand) {
+            (Value::Array(mut items), operand) => {
+                let len = items.len() as i64;
+                match operand.as_i64().map(|index| if index < 0 { index + len } else { index }) {
+                    Some(index) if (0..len).contains(&index) => {
+                        items.remove(index as usize);
+                    }
+                    Some(_) => (),
+                    None => return Err(unsupported(name, &Value::Array(items), operand)),
+                }
+                Ok(Value::Array(items))
+            }
+            (Value::Object(mut entries), Value::String(key)) => {
+                entries.remove(key);
+                Ok(Value::Object(entries))
+            }
+            (value, operand) => Err(unsupported(name, &value, operand)),
+        },
+        Update(_) => match (value, operand) {
+            (Value::Object(mut entries), Value::Object(update)) => {
+                entries.extend(update.iter().map(|(key, value)| (key.clone(), value.clone())));
+                Ok(Value::Object(entries))
+            }
+            (value, operand) => Err(unsupported(name, &value, operand)),
+        },
+    }
+}
@@ -1,9 +1,13 @@
-use serde::{Deserialize, Serialize};
+mod data_storage;
+
+pub use data_storage::{apply_operations, DataStorageError};
+
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::Value;
 use serde_repr::{Deserialize_repr, Serialize_repr};
 use std::collections::HashMap;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "cmd")]
 pub enum ClientMessage {
     Connect(Connect),
@@ -19,7 +23,7 @@ pub enum ClientMessage {
     SetNotify(SetNotify),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "cmd")]
 pub enum ServerMessage {
     RoomInfo(RoomInfo),
@@ -35,9 +39,48 @@ pub enum ServerMessage {
     InvalidPacket(InvalidPacket),
     Retrieved(Retrieved),
     SetReply(SetReply),
+    /// A message with a `cmd` this version doesn't know, or a known `cmd` whose fields failed to decode, kept as received.
+    /// Use `ServerMessage::is_known_command` to tell the two apart
+    #[serde(untagged, serialize_with = "serialize_unknown", deserialize_with = "deserialize_unknown")]
+    Unknown {
+        cmd: String,
+        raw: Value,
+    },
+}
+
+impl ServerMessage {
+    /// Whether `cmd` names one of the messages above, so a `ServerMessage::Unknown` with it failed to decode
+    pub fn is_known_command(cmd: &str) -> bool {
+        matches!(
+            cmd,
+            "RoomInfo"
+                | "ConnectionRefused"
+                | "Connected"
+                | "ReceivedItems"
+                | "LocationInfo"
+                | "RoomUpdate"
+                | "Print"
+                | "PrintJSON"
+                | "DataPackage"
+                | "Bounced"
+                | "InvalidPacket"
+                | "Retrieved"
+                | "SetReply"
+        )
+    }
+}
+
+fn serialize_unknown<S: Serializer>(_cmd: &str, raw: &Value, serializer: S) -> Result<S::Ok, S::Error> {
+    raw.serialize(serializer)
+}
+
+fn deserialize_unknown<'de, D: Deserializer<'de>>(deserializer: D) -> Result<(String, Value), D::Error> {
+    let raw = Value::deserialize(deserializer)?;
+    let cmd = raw.get("cmd").and_then(Value::as_str).unwrap_or_default().to_string();
+    Ok((cmd, raw))
 }
 
-#[derive(Debug, Serialize_repr, Deserialize_repr)]
+#[derive(Debug, Clone, Copy, Serialize_repr, Deserialize_repr)]
 #[repr(u16)]
 pub enum Permission {
     Disabled = 0,
@@ -47,7 +90,7 @@ pub enum Permission {
     AutoEnabled = 7,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NetworkVersion {
     pub major: i32,
     pub minor: i32,
@@ -55,7 +98,7 @@ pub struct NetworkVersion {
     pub class: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NetworkPlayer {
     pub team: i32,
     pub slot: i32,
@@ -63,7 +106,7 @@ pub struct NetworkPlayer {
     pub name: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NetworkItem {
     pub item: i64,
     pub location: i64,
@@ -71,7 +114,7 @@ pub struct NetworkItem {
     pub flags: i64,
 }
 
-#[derive(Debug, Serialize_repr, Deserialize_repr)]
+#[derive(Debug, Clone, Copy, Serialize_repr, Deserialize_repr)]
 #[repr(u16)]
 pub enum SlotType {
     Spectator = 0,
@@ -79,7 +122,7 @@ pub enum SlotType {
     Group = 2,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NetworkSlot {
     pub name: String,
     pub game: String,
@@ -98,7 +141,7 @@ pub fn network_version() -> NetworkVersion {
 
 // REQUESTS
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Connect {
     pub password: Option<String>,
     pub name: String,
@@ -109,29 +152,29 @@ pub struct Connect {
     pub game: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConnectUpdate {
     pub items_handling: i32,
     pub tags: Vec<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LocationChecks {
     pub locations: Vec<i64>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LocationScouts {
     pub locations: Vec<i64>,
     pub create_as_hint: i32,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StatusUpdate {
     pub status: ClientStatus,
 }
 
-#[derive(Debug, Serialize_repr, Deserialize_repr)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize_repr, Deserialize_repr)]
 #[repr(u16)]
 pub enum ClientStatus {
     Unknown = 0,
@@ -140,18 +183,18 @@ pub enum ClientStatus {
     Goal = 30,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Say {
     pub text: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GetDataPackage {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub games: Option<Box<[String]>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Bounce {
     pub games: Option<Vec<String>>,
     pub slots: Option<Vec<String>>,
@@ -159,33 +202,131 @@ pub struct Bounce {
     pub data: Value,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Get {
     pub keys: Vec<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Set {
     pub key: String,
     pub default: Value,
     pub want_reply: bool,
     pub operations: Vec<DataStorageOperation>,
+    /// Extra arguments, which the server passes back in the `SetReply`
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, Value>,
+}
+
+/// Operations applied in order to the stored value of a `Set`, see `apply_operations`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "RawOperation", into = "RawOperation")]
+pub enum DataStorageOperation {
+    Replace(Value),
+    Default,
+    Add(Value),
+    Mul(Value),
+    Pow(Value),
+    Mod(Value),
+    Floor,
+    Ceil,
+    Max(Value),
+    Min(Value),
+    And(Value),
+    Or(Value),
+    Xor(Value),
+    LeftShift(Value),
+    RightShift(Value),
+    Remove(Value),
+    Pop(Value),
+    Update(Value),
+}
+
+// The server reads `value` for every operation, so it is always sent even when ignored
+#[derive(Serialize, Deserialize)]
+struct RawOperation {
+    operation: String,
+    #[serde(default)]
+    value: Value,
+}
+
+impl TryFrom<RawOperation> for DataStorageOperation {
+    type Error = String;
+
+    fn try_from(raw: RawOperation) -> Result<Self, Self::Error> {
+        use DataStorageOperation::*;
+        let value = raw.value;
+        Ok(match raw.operation.as_str() {
+            "replace" => Replace(value),
+            "default" => Default,
+            "add" => Add(value),
+            "mul" => Mul(value),
+            "pow" => Pow(value),
+            "mod" => Mod(value),
+            "floor" => Floor,
+            "ceil" => Ceil,
+            "max" => Max(value),
+            "min" => Min(value),
+            "and" => And(value),
+            "or" => Or(value),
+            "xor" => Xor(value),
+            "left_shift" => LeftShift(value),
+            "right_shift" => RightShift(value),
+            "remove" => Remove(value),
+            "pop" => Pop(value),
+            "update" => Update(value),
+            operation => return Err(format!("unknown data storage operation {operation}")),
+        })
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct DataStorageOperation {
-    pub replace: String, // TODO: enum-ify?
-    pub value: Value,
+impl DataStorageOperation {
+    pub fn name(&self) -> &'static str {
+        use DataStorageOperation::*;
+        match self {
+            Replace(_) => "replace",
+            Default => "default",
+            Add(_) => "add",
+            Mul(_) => "mul",
+            Pow(_) => "pow",
+            Mod(_) => "mod",
+            Floor => "floor",
+            Ceil => "ceil",
+            Max(_) => "max",
+            Min(_) => "min",
+            And(_) => "and",
+            Or(_) => "or",
+            Xor(_) => "xor",
+            LeftShift(_) => "left_shift",
+            RightShift(_) => "right_shift",
+            Remove(_) => "remove",
+            Pop(_) => "pop",
+            Update(_) => "update",
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl From<DataStorageOperation> for RawOperation {
+    fn from(operation: DataStorageOperation) -> Self {
+        use DataStorageOperation::*;
+        let name = operation.name();
+        let value = match operation {
+            Default | Floor | Ceil => Value::Null,
+            Replace(value) | Add(value) | Mul(value) | Pow(value) | Mod(value) | Max(value) | Min(value) | And(value) | Or(value) | Xor(value) | LeftShift(value) | RightShift(value)
+            | Remove(value) | Pop(value) | Update(value) => value,
+        };
+        RawOperation { operation: name.into(), value }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SetNotify {
     pub keys: Vec<String>,
 }
 
 // RESPONSES
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RoomInfo {
     pub version: NetworkVersion,
     pub tags: Vec<String>,
@@ -199,12 +340,42 @@ pub struct RoomInfo {
     pub time: f32,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConnectionRefused {
     pub errors: Vec<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl ConnectionRefused {
+    pub fn reasons(&self) -> Vec<RefusalReason> {
+        self.errors.iter().map(|error| RefusalReason::from(error.as_str())).collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RefusalReason {
+    InvalidSlot,
+    InvalidGame,
+    IncompatibleVersion,
+    InvalidPassword,
+    InvalidItemsHandling,
+    /// A reason added after this version of the protocol
+    Other(String),
+}
+
+impl From<&str> for RefusalReason {
+    fn from(error: &str) -> Self {
+        match error {
+            "InvalidSlot" => RefusalReason::InvalidSlot,
+            "InvalidGame" => RefusalReason::InvalidGame,
+            "IncompatibleVersion" => RefusalReason::IncompatibleVersion,
+            "InvalidPassword" => RefusalReason::InvalidPassword,
+            "InvalidItemsHandling" => RefusalReason::InvalidItemsHandling,
+            other => RefusalReason::Other(other.into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Connected {
     pub team: i32,
     pub slot: i32,
@@ -224,18 +395,18 @@ pub struct SlotData {
     pub locations_per: [i8; 6],
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ReceivedItems {
     pub index: i32,
     pub items: Vec<NetworkItem>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LocationInfo {
     pub locations: Vec<NetworkItem>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct RoomUpdate {
     // Copied from RoomInfo
     pub version: Option<NetworkVersion>,
@@ -254,12 +425,12 @@ pub struct RoomUpdate {
     pub missing_locations: Option<Vec<i64>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Print {
     pub text: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PrintJSON {
     pub data: Vec<JSONMessagePart>,
     pub r#type: Option<String>,
@@ -269,7 +440,7 @@ pub struct PrintJSON {
     pub countdown: Option<i32>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct JSONMessagePart {
     pub r#type: Option<String>,
     pub text: Option<String>,
@@ -278,23 +449,43 @@ pub struct JSONMessagePart {
     pub player: Option<i32>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DataPackage {
     pub data: DataPackageObject,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DataPackageObject {
     pub games: HashMap<String, GameData>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct GameData {
     pub item_name_to_id: HashMap<String, i64>,
     pub location_name_to_id: HashMap<String, i64>,
+    /// Only sent by newer servers, and only sent back if it was received so the checksum stays the same
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub item_name_groups: Option<HashMap<String, Vec<String>>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location_name_groups: Option<HashMap<String, Vec<String>>>,
+    /// Every other field the server sent, like the checksum, kept so the checksum can be verified
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, Value>,
+}
+
+impl GameData {
+    /// The checksum the server advertises in `RoomInfo::datapackage_checksums`: the sha1 of the game's data without
+    /// its `checksum` field, as JSON with sorted keys and no whitespace
+    pub fn checksum(&self) -> String {
+        let mut value = serde_json::to_value(self).expect("game data always serializes");
+        if let Value::Object(fields) = &mut value {
+            fields.remove("checksum");
+        }
+        sha1_smol::Sha1::from(value.to_string()).digest().to_string()
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Bounced {
     pub games: Vec<String>,
     pub slots: Vec<i32>,
@@ -302,21 +493,86 @@ pub struct Bounced {
     pub data: Value,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InvalidPacket {
     pub r#type: String,
     pub original_cmd: Option<String>,
     pub text: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Retrieved {
-    keys: Value,
+    keys: serde_json::Map<String, Value>,
+}
+
+impl Retrieved {
+    pub fn new(keys: serde_json::Map<String, Value>) -> Self {
+        Retrieved { keys }
+    }
+
+    /// Every requested key with its value, keys without a value are `null`
+    pub fn keys(&self) -> &serde_json::Map<String, Value> {
+        &self.keys
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.keys.get(key)
+    }
+
+    /// Deserialize the value of `key`. Keys that weren't requested are treated as `null`, like keys without a value
+    pub fn get_as<T: DeserializeOwned>(&self, key: &str) -> Result<T, serde_json::Error> {
+        T::deserialize(self.keys.get(key).unwrap_or(&Value::Null))
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SetReply {
     key: String,
     value: Value,
     original_value: Value,
+    #[serde(flatten)]
+    extra: serde_json::Map<String, Value>,
+}
+
+impl SetReply {
+    pub fn new(key: String, value: Value, original_value: Value) -> Self {
+        SetReply {
+            key,
+            value,
+            original_value,
+            extra: serde_json::Map::new(),
+        }
+    }
+
+    /// The reply to a `Set` with extra arguments, which are passed back along with it
+    pub fn with_extra(mut self, extra: serde_json::Map<String, Value>) -> Self {
+        self.extra = extra;
+        self
+    }
+
+    /// The extra arguments of the `Set` this is the reply to, and whatever else the server sent along
+    pub fn extra(&self) -> &serde_json::Map<String, Value> {
+        &self.extra
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn value(&self) -> &Value {
+        &self.value
+    }
+
+    /// The value before the `Set` was applied, `null` if the key had no value
+    pub fn original_value(&self) -> &Value {
+        &self.original_value
+    }
+
+    pub fn value_as<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        T::deserialize(&self.value)
+    }
+
+    pub fn original_value_as<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        T::deserialize(&self.original_value)
+    }
 }
@@ -0,0 +1,132 @@
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+/// Overrides the platform data dir
+pub const DATA_DIR_ENV: &str = "SOTM_AP_DATA_DIR";
+/// Picks the profile when none is given
+pub const PROFILE_ENV: &str = "SOTM_AP_PROFILE";
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Where the CLI keeps its data. Saves are kept per profile, while the datapackage cache is shared by all of them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataDir {
+    root: PathBuf,
+    profile: String,
+}
+
+impl DataDir {
+    /// Uses the first of `root`, `$SOTM_AP_DATA_DIR` and the platform data dir (`$XDG_DATA_HOME/sotm-archipelago` on
+    /// Linux), or the working directory if there is none. The profile is the first of `profile`, `$SOTM_AP_PROFILE` and
+    /// `default`
+    pub fn new(root: Option<PathBuf>, profile: Option<String>) -> Result<Self, String> {
+        let root = root
+            .or_else(|| env::var_os(DATA_DIR_ENV).filter(|dir| !dir.is_empty()).map(PathBuf::from))
+            .or_else(|| dirs::data_dir().map(|dir| dir.join("sotm-archipelago")))
+            .unwrap_or_else(|| PathBuf::from("."));
+        let profile = profile
+            .or_else(|| env::var(PROFILE_ENV).ok().filter(|profile| !profile.is_empty()))
+            .unwrap_or_else(|| DEFAULT_PROFILE.to_string());
+
+        if !is_valid_profile(&profile) {
+            return Err(format!("Invalid profile name {profile:?}, only letters, digits, - and _ are allowed"));
+        }
+
+        Ok(DataDir { root, profile })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn profile(&self) -> &str {
+        &self.profile
+    }
+
+    pub fn datapackage(&self) -> PathBuf {
+        self.root.join("datapackage")
+    }
+
+    /// The log of the last run, shared by all profiles like the datapackage cache
+    pub fn log(&self) -> PathBuf {
+        self.root.join("client.log")
+    }
+
+    pub fn persistent(&self) -> PathBuf {
+        self.root.join("profiles").join(&self.profile).join("persistent")
+    }
+
+    /// Saves of replayed sessions, so a replay never touches the real save of the recorded seed. Thrown away before
+    /// every replay
+    pub fn replay(&self) -> PathBuf {
+        self.root.join("replay")
+    }
+}
+
+// Profiles name a directory, so anything that could leave it is out
+pub fn is_valid_profile(profile: &str) -> bool {
+    !profile.is_empty() && profile.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data::Villain,
+        persistent::{DefaultPersistentStore, PersistentStore, SlotSave},
+        test_support::{key, TempDir},
+    };
+
+    // Everything reading the environment stays in this one test
+    #[test]
+    fn flags_then_environment_then_defaults() {
+        env::remove_var(DATA_DIR_ENV);
+        env::remove_var(PROFILE_ENV);
+        let default = DataDir::new(None, None).unwrap();
+        assert_eq!(default.profile(), DEFAULT_PROFILE);
+        assert!(default.root().ends_with("sotm-archipelago"));
+
+        env::set_var(DATA_DIR_ENV, "/from/env");
+        env::set_var(PROFILE_ENV, "alice");
+        let from_env = DataDir::new(None, None).unwrap();
+        assert_eq!(from_env.datapackage(), PathBuf::from("/from/env/datapackage"));
+        assert_eq!(from_env.persistent(), PathBuf::from("/from/env/profiles/alice/persistent"));
+
+        let from_flags = DataDir::new(Some(PathBuf::from("/from/flag")), Some(String::from("bob"))).unwrap();
+        assert_eq!(from_flags.root(), PathBuf::from("/from/flag"));
+        assert_eq!(from_flags.profile(), "bob");
+
+        env::set_var(PROFILE_ENV, "../escape");
+        assert!(DataDir::new(None, None).is_err());
+        env::remove_var(DATA_DIR_ENV);
+        env::remove_var(PROFILE_ENV);
+    }
+
+    #[test]
+    fn invalid_profiles_are_rejected() {
+        for profile in ["", "a/b", "..", "a b"] {
+            assert!(DataDir::new(Some(PathBuf::from("/data")), Some(profile.to_string())).is_err(), "{profile:?}");
+        }
+        assert!(DataDir::new(Some(PathBuf::from("/data")), Some(String::from("Player_2-b"))).is_ok());
+    }
+
+    #[test]
+    fn profiles_keep_separate_saves() {
+        let temp = TempDir::new("profiles");
+        let root = temp.path().to_path_buf();
+        let alice = DataDir::new(Some(root.clone()), Some(String::from("alice"))).unwrap();
+        let bob = DataDir::new(Some(root.clone()), Some(String::from("bob"))).unwrap();
+        assert_eq!(alice.datapackage(), bob.datapackage());
+
+        let mut save = SlotSave::default();
+        save.locations.mark_villain(Villain::BaronBlade, 0);
+        save.items_index = 3;
+        DefaultPersistentStore::in_dir(alice.persistent(), &key(1)).save(&save);
+
+        let loaded = DefaultPersistentStore::in_dir(alice.persistent(), &key(1)).load();
+        assert_eq!(loaded.items_index, 3);
+        assert!(!loaded.locations.has_unchecked_villain(Villain::BaronBlade, 0));
+        assert_eq!(DefaultPersistentStore::in_dir(bob.persistent(), &key(1)).load().items_index, 0);
+    }
+}
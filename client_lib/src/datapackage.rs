@@ -1,14 +1,16 @@
 use crate::data::{Item, Location};
-use archipelago_protocol::{Connected, DataPackageObject, GameData as ArchipelagoGameData};
-use serde_json::{from_reader, to_writer};
+use archipelago_protocol::{Connected, DataPackageObject, GameData as ArchipelagoGameData, PrintJSON};
+use serde_json::{from_str, to_string};
 use std::{
-    collections::HashMap,
-    fs::{create_dir, create_dir_all, remove_file, File},
-    io::{stdout, Write},
-    path::Path,
+    collections::{HashMap, HashSet},
+    fs::{create_dir_all, read_to_string, remove_file, rename, File},
+    io::{self, Write},
+    path::PathBuf,
     sync::Arc,
 };
 
+pub const GAME: &str = "Sentinels of the Multiverse";
+
 pub type Requested = HashMap<String, String>;
 
 pub trait DatapackageStore {
@@ -16,22 +18,98 @@ pub trait DatapackageStore {
     fn missing_games(&self) -> Box<[String]>;
     fn cache(&mut self, data: DataPackageObject);
     fn build_player_map(&mut self, connected: &Connected);
+    /// Games whose data is needed to show this message but isn't loaded, each reported once so it's only fetched once.
+    /// Once fetched and cached, the message can be formatted again
+    fn report_misses(&mut self, msg: &PrintJSON) -> Vec<String>;
     fn get_item(&self, player: i32, id: i64) -> &str;
     fn get_location(&self, player: i32, id: i64) -> &str;
+    /// Item name groups of a game by group name, if the game is loaded
+    fn item_groups(&self, game: &str) -> Option<&HashMap<String, Vec<String>>>;
+    /// Location name groups of a game by group name, if the game is loaded
+    fn location_groups(&self, game: &str) -> Option<&HashMap<String, Vec<String>>>;
     fn id_to_own_item(&self, id: i64) -> Option<Item>;
     fn id_to_own_location(&self, id: i64) -> Option<Location>;
     fn id_from_own_item(&self, item: Item) -> Option<i64>;
     fn id_from_own_location(&self, location: (Location, u8)) -> Option<i64>;
 }
 
+/// Where downloaded game data is cached between sessions, as the JSON the server sent
+pub trait DatapackageBackend {
+    fn load(&self, game: &str, checksum: &str) -> Option<String>;
+    fn store(&mut self, game: &str, checksum: &str, data: &str) -> io::Result<()>;
+}
+
+/// Caches under `<root>/<game>/<checksum>`, with `./datapackage` as the default root
+#[derive(Debug, Clone)]
+pub struct FilesystemBackend {
+    root: PathBuf,
+}
+
+impl FilesystemBackend {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        FilesystemBackend { root: root.into() }
+    }
+}
+
+impl Default for FilesystemBackend {
+    fn default() -> Self {
+        FilesystemBackend::new("./datapackage")
+    }
+}
+
+impl DatapackageBackend for FilesystemBackend {
+    fn load(&self, game: &str, checksum: &str) -> Option<String> {
+        read_to_string(self.root.join(game).join(checksum)).ok()
+    }
+
+    // Writes to a temporary file next to the cached one and renames it over, so a crash never leaves half a file behind
+    fn store(&mut self, game: &str, checksum: &str, data: &str) -> io::Result<()> {
+        let dir = self.root.join(game);
+        create_dir_all(&dir)?;
+        let path = dir.join(checksum);
+        let temp = path.with_extension("tmp");
+
+        let res = File::create(&temp).and_then(|mut file| {
+            file.write_all(data.as_bytes())?;
+            file.sync_all()
+        });
+        let res = res.and_then(|_| rename(&temp, &path));
+        if res.is_err() {
+            let _ = remove_file(&temp);
+        }
+        res
+    }
+}
+
+/// Keeps the cache for as long as the backend lives, e.g. for tests
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBackend {
+    games: HashMap<(String, String), String>,
+}
+
+impl DatapackageBackend for MemoryBackend {
+    fn load(&self, game: &str, checksum: &str) -> Option<String> {
+        self.games.get(&(game.to_string(), checksum.to_string())).cloned()
+    }
+
+    fn store(&mut self, game: &str, checksum: &str, data: &str) -> io::Result<()> {
+        self.games.insert((game.to_string(), checksum.to_string()), data.to_string());
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone)]
 struct GameData {
     item_id_to_name: HashMap<i64, String>,
     location_id_to_name: HashMap<i64, String>,
+    item_name_groups: HashMap<String, Vec<String>>,
+    location_name_groups: HashMap<String, Vec<String>>,
 }
 
+/// The datapackage store of both frontends, differing only in where the cache is kept
 #[derive(Debug, Clone)]
-pub struct DefaultDatapackageStore {
+pub struct CachedDatapackageStore<B> {
+    backend: B,
     items_from_id: HashMap<i64, Item>,
     items_to_id: HashMap<Item, i64>,
     locations_from_id: HashMap<i64, (Location, u8)>,
@@ -39,12 +117,60 @@ pub struct DefaultDatapackageStore {
     data: HashMap<String, Arc<GameData>>,
     missing: Vec<String>,
     missing_checksums: Vec<String>,
+    player_games: HashMap<i32, String>,
     player_to_game: HashMap<i32, Arc<GameData>>,
+    reported: HashSet<String>,
 }
 
-impl DefaultDatapackageStore {
+pub type DefaultDatapackageStore = CachedDatapackageStore<FilesystemBackend>;
+
+impl<B: DatapackageBackend> CachedDatapackageStore<B> {
+    pub fn with_backend(backend: B, requested: Requested) -> Self {
+        let mut new = Self {
+            backend,
+            data: HashMap::new(),
+            missing: vec![],
+            missing_checksums: vec![],
+            player_games: HashMap::new(),
+            player_to_game: HashMap::new(),
+            reported: HashSet::new(),
+            items_from_id: HashMap::new(),
+            items_to_id: HashMap::new(),
+            locations_from_id: HashMap::new(),
+            locations_to_id: HashMap::new(),
+        };
+
+        for (game, checksum) in requested {
+            // Anything that doesn't parse or doesn't hash to the checksum it's stored under is treated as not cached
+            let cached = new.backend.load(&game, &checksum).and_then(|data| from_str::<ArchipelagoGameData>(&data).ok());
+            match cached {
+                Some(data) if data.checksum() == checksum => new.add_game(game, data),
+                _ => {
+                    new.missing.push(game);
+                    new.missing_checksums.push(checksum);
+                }
+            }
+        }
+
+        new
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// The name of an item of a game, whoever's it is
+    pub fn game_item(&self, game: &str, id: i64) -> Option<&str> {
+        self.data.get(game)?.item_id_to_name.get(&id).map(String::as_str)
+    }
+
+    /// The name of a location of a game, whoever's it is
+    pub fn game_location(&self, game: &str, id: i64) -> Option<&str> {
+        self.data.get(game)?.location_id_to_name.get(&id).map(String::as_str)
+    }
+
     fn add_game(&mut self, game: String, data: ArchipelagoGameData) {
-        if *game == *"Sentinels of the Multiverse" {
+        if *game == *GAME {
             for (item, id) in &data.item_name_to_id {
                 if let Some(item) = Item::from_str(item) {
                     self.items_from_id.insert(*id, item);
@@ -70,64 +196,45 @@ impl DefaultDatapackageStore {
             location_id_to_name.insert(id, location);
         }
 
-        self.data.insert(game, Arc::new(GameData { item_id_to_name, location_id_to_name }));
+        // Players of a game that was missing when the player map was built get it now
+        let data = Arc::new(GameData {
+            item_id_to_name,
+            location_id_to_name,
+            item_name_groups: data.item_name_groups.unwrap_or_default(),
+            location_name_groups: data.location_name_groups.unwrap_or_default(),
+        });
+        for (player, _) in self.player_games.iter().filter(|(_, player_game)| **player_game == game) {
+            self.player_to_game.insert(*player, data.clone());
+        }
+        self.data.insert(game, data);
     }
 }
 
-impl DatapackageStore for DefaultDatapackageStore {
+impl<B: DatapackageBackend + Default> DatapackageStore for CachedDatapackageStore<B> {
     fn new(requested: Requested) -> Self {
-        let mut new = Self {
-            data: HashMap::new(),
-            missing: vec![],
-            missing_checksums: vec![],
-            player_to_game: HashMap::new(),
-            items_from_id: HashMap::new(),
-            items_to_id: HashMap::new(),
-            locations_from_id: HashMap::new(),
-            locations_to_id: HashMap::new(),
-        };
-
-        for (game, checksum) in requested {
-            if let Ok(reader) = File::open(Path::new("./datapackage").join(&game).join(&checksum)) {
-                if let Ok(data) = from_reader::<File, archipelago_protocol::GameData>(reader) {
-                    new.add_game(game, data);
-                } else {
-                    new.missing.push(game);
-                    new.missing_checksums.push(checksum);
-                }
-            } else {
-                new.missing.push(game);
-                new.missing_checksums.push(checksum);
-            }
-        }
-
-        new
+        Self::with_backend(B::default(), requested)
     }
 
     fn missing_games(&self) -> Box<[String]> {
-        let mut lock = stdout().lock();
-        for game in &self.missing {
-            let _ = writeln!(lock, "Missing datapackage for {game}...");
-        }
-
         self.missing.clone().into()
     }
 
     fn cache(&mut self, data: DataPackageObject) {
-        create_dir_all("./datapackage").expect("Failed to create datapackage cache");
-
         for (game, checksum) in self.missing.iter().zip(&self.missing_checksums) {
             if let Some(data) = data.games.get(game) {
-                let _ = create_dir(Path::new("./datapackage").join(game));
-                if let Ok(writer) = File::create(Path::new("./datapackage").join(game).join(checksum)) {
-                    let res = to_writer(writer, data);
-                    if res.is_err() {
-                        remove_file(Path::new("./datapackage").join(game).join(checksum)).unwrap_or_else(|_| panic!("Failed to clean failed write for {game}-{checksum}. Data may be corrupt"));
-                    }
+                // Still used for this session, but caching it would only have it refetched next time
+                if data.checksum() != *checksum {
+                    log::warn!("Datapackage for {game} doesn't match its checksum, not caching it");
+                } else if let Err(err) = to_string(data).map_err(io::Error::from).and_then(|json| self.backend.store(game, checksum, &json)) {
+                    log::warn!("Failed to cache datapackage for {game} with error {err}");
                 }
             }
         }
 
+        let (missing, missing_checksums) = self.missing.drain(..).zip(self.missing_checksums.drain(..)).filter(|(game, _)| !data.games.contains_key(game)).unzip();
+        self.missing = missing;
+        self.missing_checksums = missing_checksums;
+
         for (game, data) in data.games {
             self.add_game(game, data);
         }
@@ -139,10 +246,24 @@ impl DatapackageStore for DefaultDatapackageStore {
                 if let Some(rc) = self.data.get(&*slot.game) {
                     self.player_to_game.insert(player, rc.clone());
                 }
+                self.player_games.insert(player, slot.game.clone());
             }
         }
     }
 
+    fn report_misses(&mut self, msg: &PrintJSON) -> Vec<String> {
+        let mut misses = vec![];
+        for part in msg.data.iter().filter(|part| matches!(part.r#type.as_deref(), Some("item_id" | "location_id"))) {
+            if let Some(game) = part.player.and_then(|player| self.player_games.get(&player)) {
+                if !self.data.contains_key(game) && self.reported.insert(game.clone()) {
+                    misses.push(game.clone());
+                }
+            }
+        }
+
+        misses
+    }
+
     fn get_item(&self, player: i32, id: i64) -> &str {
         if let Some(data) = self.player_to_game.get(&player) {
             if let Some(item) = data.item_id_to_name.get(&id) {
@@ -163,6 +284,14 @@ impl DatapackageStore for DefaultDatapackageStore {
         "Unknown location"
     }
 
+    fn item_groups(&self, game: &str) -> Option<&HashMap<String, Vec<String>>> {
+        self.data.get(game).map(|data| &data.item_name_groups)
+    }
+
+    fn location_groups(&self, game: &str) -> Option<&HashMap<String, Vec<String>>> {
+        self.data.get(game).map(|data| &data.location_name_groups)
+    }
+
     fn id_to_own_item(&self, id: i64) -> Option<Item> {
         self.items_from_id.get(&id).copied()
     }
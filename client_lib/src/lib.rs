@@ -1,29 +1,52 @@
 pub mod data;
+pub mod data_dir;
 pub mod datapackage;
 mod logic;
 pub mod persistent;
 pub mod state;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
 
-use archipelago_protocol::{Connected, PrintJSON};
+use archipelago_protocol::{Connected, DataPackageObject, PrintJSON};
 use data::Location;
 use datapackage::DatapackageStore;
-use persistent::PersistentStore;
-use state::State;
-use std::collections::HashMap;
+use persistent::{PersistentStore, SaveKey, SlotSave};
+use serde_json::Value;
+use state::{Items, Locations, State};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Connection {
+    Connected,
+    Reconnecting(u32),
+    /// The server refused to reconnect, so there won't be any more attempts
+    Refused,
+    /// The replay is over, what's shown is where the recording left off
+    Ended,
+}
 
 #[derive(Debug)]
 pub enum Update {
     Msg(PrintJSON),
-    Items(Vec<i64>),
+    Items(i32, Vec<i64>),
+    Locations(Vec<i64>, Vec<i64>),
+    DataPackage(DataPackageObject),
+    Connection(Connection),
     Send(Vec<Location>),
+    MultiSend(bool),
+    /// The save shared through data storage changed
+    SharedSave(Value),
     Exit,
 }
 
 #[allow(clippy::large_enum_variant)] // Boxing State wouldn't do much since most updates are state updates anyways
 #[derive(Debug)]
-pub enum DisplayUpdate {
+pub enum DisplayUpdate<D: DatapackageStore> {
     Msg(PrintJSON),
     State(State),
+    /// The datapackage after fetching games that were missing, messages so far should be formatted again
+    Datapackage(D),
+    Connection(Connection),
     Exit,
 }
 
@@ -37,6 +60,30 @@ where
     pub state: State,
     pub players: HashMap<i32, String>,
     pub slot: String,
+    pub items_index: i32,
+    /// The last messages received, oldest first, kept so they can be shown again after a restart
+    pub messages: VecDeque<PrintJSON>,
+    pub multi_send: bool,
+    /// Locations sent to the server that it hasn't reported as checked yet, by location id
+    unconfirmed: BTreeMap<i64, Location>,
+}
+
+/// How many messages `Session::log_message` keeps
+pub const MESSAGE_LOG_LEN: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ItemSync {
+    Applied,
+    Gap,
+}
+
+/// What `Session::merge_locations` found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocationMerge {
+    /// The session got checks it didn't have, so the local save is behind
+    pub gained: bool,
+    /// The session has checks the merged locations are missing, so they should be shared
+    pub ahead: bool,
 }
 
 impl<D, P> Session<D, P>
@@ -45,23 +92,163 @@ where
     P: PersistentStore,
 {
     pub fn new(seed_name: &str, datapackage_store: D, connected: Connected, slot: &str) -> Session<D, P> {
-        let persistent_store = P::new(seed_name);
+        let key = SaveKey {
+            seed: seed_name.to_string(),
+            team: connected.team,
+            slot: connected.slot,
+        };
+        Session::with_stores(datapackage_store, P::new(&key), connected, slot)
+    }
 
+    pub fn with_stores(datapackage_store: D, persistent_store: P, connected: Connected, slot: &str) -> Session<D, P> {
         let mut state = State::new(connected.slot_data);
 
-        state.checked_locations = persistent_store.load();
+        // Items are applied again as the server sends them, until then the saved ones are shown
+        let save = persistent_store.load();
+        state.checked_locations = save.locations;
+        state.items = save.items;
 
         let mut players = HashMap::new();
         for player in connected.players {
             players.insert(player.slot, player.alias);
         }
 
-        Session {
+        let mut session = Session {
             datapackage_store,
             persistent_store,
             state,
             players,
             slot: slot.to_string(),
+            items_index: save.items_index,
+            messages: save.messages.into(),
+            multi_send: save.multi_send,
+            unconfirmed: BTreeMap::new(),
+        };
+
+        session.update_locations(&connected.checked_locations, &connected.missing_locations);
+        session
+    }
+
+    /// Merges location ids reported by the server into the checked locations.
+    ///
+    /// The server is authoritative, so locations it reports as missing are unmarked even if they were saved as checked.
+    /// Sent locations the server hasn't confirmed yet are the exception, after a reconnect the server reports them as
+    /// missing until they've been sent again. Locations without any ids, like victory, are left as they are.
+    ///
+    /// Returns whether sent locations were confirmed or the checked locations changed, in which case it should be saved
+    pub fn update_locations(&mut self, checked: &[i64], missing: &[i64]) -> bool {
+        let before = self.state.checked_locations;
+        let mut confirmed = false;
+        for id in checked {
+            confirmed |= self.unconfirmed.remove(id).is_some();
+        }
+
+        for location in checked.iter().filter_map(|id| self.datapackage_store.id_to_own_location(*id)) {
+            self.state.checked_locations.mark_location(location);
+        }
+
+        for location in missing
+            .iter()
+            .filter(|id| !self.unconfirmed.contains_key(id))
+            .filter_map(|id| self.datapackage_store.id_to_own_location(*id))
+        {
+            self.state.checked_locations.unmark_location(location);
+        }
+
+        confirmed || self.state.checked_locations != before
+    }
+
+    /// Marks `locations` as checked and returns the location ids to send for them in a `LocationChecks`.
+    ///
+    /// The marks are provisional: the server hasn't got the checks yet, and they are only confirmed once it reports
+    /// them as checked through `update_locations`. Victory has no ids and is left to the caller
+    pub fn send_locations(&mut self, locations: &[Location]) -> Vec<i64> {
+        let mut location_ids = vec![];
+
+        for &location in locations.iter().filter(|location| **location != Location::Victory) {
+            let per = self.state.slot_data.locations_per[match location {
+                Location::Variant(_) => 5,
+                Location::Villain((_, d)) | Location::TeamVillain((_, d)) => d as usize,
+                Location::Environment(_) => 4,
+                Location::Victory => unreachable!(),
+            }];
+            // Checks the server already has won't be reported again
+            let checked = self.state.checked_locations.is_checked(location);
+            self.state.checked_locations.mark_location(location);
+
+            for id in (1..=per).filter_map(|n| self.datapackage_store.id_from_own_location((location, n))) {
+                if !checked {
+                    self.unconfirmed.insert(id, location);
+                }
+                location_ids.push(id);
+            }
+        }
+
+        location_ids
+    }
+
+    /// Marks locations checked by another client playing the slot, like from a save shared through data storage.
+    ///
+    /// Returns whether that gave the session new checks, and whether the session has checks that `locations` is missing,
+    /// in which case it should be saved so the other client gets them in turn
+    pub fn merge_locations(&mut self, locations: &Locations) -> LocationMerge {
+        let before = self.state.checked_locations;
+        self.state.checked_locations.merge(locations);
+        LocationMerge {
+            gained: self.state.checked_locations != before,
+            ahead: self.state.checked_locations != *locations,
+        }
+    }
+
+    /// Applies a `ReceivedItems` packet, skipping items that were already applied.
+    ///
+    /// An index of 0 is a full resync and replaces the current items. Returns `ItemSync::Gap` without applying anything
+    /// if items between the last applied index and `index` are missing, in which case a `Sync` should be requested
+    pub fn receive_items(&mut self, index: i32, item_ids: &[i64]) -> ItemSync {
+        if index == 0 {
+            self.state.items = Items::new();
+            self.items_index = 0;
+        } else if index > self.items_index {
+            return ItemSync::Gap;
+        }
+
+        for id in item_ids.iter().skip((self.items_index - index) as usize) {
+            if let Some(item) = self.datapackage_store.id_to_own_item(*id) {
+                self.state.items.set_item(item);
+            }
+        }
+
+        self.items_index = self.items_index.max(index + item_ids.len() as i32);
+        ItemSync::Applied
+    }
+
+    pub fn log_message(&mut self, msg: PrintJSON) {
+        if self.messages.len() >= MESSAGE_LOG_LEN {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(msg);
+    }
+
+    /// Saves everything but the sent locations the server hasn't confirmed yet, a save never has checks the server might
+    /// not have
+    pub fn save(&self) {
+        self.persistent_store.save(&self.to_save());
+    }
+
+    /// What `save` hands to the persistent store
+    pub fn to_save(&self) -> SlotSave {
+        let mut locations = self.state.checked_locations;
+        for location in self.unconfirmed.values() {
+            locations.unmark_location(*location);
+        }
+
+        SlotSave {
+            locations,
+            items: self.state.items,
+            items_index: self.items_index,
+            slot_data: Some(self.state.slot_data),
+            messages: self.messages.iter().cloned().collect(),
+            multi_send: self.multi_send,
         }
     }
 }
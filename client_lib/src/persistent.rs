@@ -1,72 +1,821 @@
 use crate::{
-    data::{TeamVillain, Villain},
-    state::Locations,
+    data::{Environment, Hero, Item, TeamVillain, Variant, Villain},
+    datapackage::GAME,
+    state::{CleanedSlotData, Items, Locations},
 };
+use archipelago_protocol::{DataStorageOperation, NetworkSlot, PrintJSON, Set, SlotType};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_slice, to_value, to_vec_pretty, Value};
 use std::{
-    fs::{create_dir_all, rename, File},
-    io::{Read, Write},
-    path::Path,
+    cell::Cell,
+    collections::{BTreeMap, HashMap},
+    fs::{copy, create_dir_all, metadata, read, remove_file, rename, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::Duration,
 };
+use strum::IntoEnumIterator;
 
 pub trait PersistentStore {
-    fn new(seed: &str) -> Self;
-    fn load(&self) -> Locations;
-    fn save(&self, locations: &Locations);
+    fn new(key: &SaveKey) -> Self;
+    fn load(&self) -> SlotSave;
+    fn save(&self, save: &SlotSave);
 }
 
+/// What a save belongs to, one slot of one team in one multiworld
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SaveKey {
+    pub seed: String,
+    pub team: i32,
+    pub slot: i32,
+}
+
+impl SaveKey {
+    /// The name the save is kept under, `<seed>-<team>-<slot>`
+    pub fn name(&self) -> String {
+        format!("{}-{}-{}", self.seed, self.team, self.slot)
+    }
+
+    /// The data storage key the save is kept under on the server. Data storage belongs to the room, so unlike `name` it
+    /// leaves out the seed
+    pub fn storage_key(&self) -> String {
+        format!("sotm_ap_save_{}_{}", self.team, self.slot)
+    }
+}
+
+/// Whether the multiworld described by `slot_info` from `Connected` has only one player of the game. Saves from before
+/// they were kept per slot are named after the seed alone, so they can only be adopted by a slot when that's the case
+pub fn sole_slot_of_game(slot_info: &HashMap<String, NetworkSlot>) -> bool {
+    slot_info.values().filter(|slot| slot.game == GAME && matches!(slot.r#type, SlotType::Player)).count() == 1
+}
+
+/// What a seed-only save is renamed to once a slot adopted it, so it isn't adopted again
+pub fn adopted_name(seed: &str) -> String {
+    format!("{seed}-adopted")
+}
+
+/// Slot state that is kept between sessions, enough to show where the slot was left before the server is reached
+#[derive(Debug, Clone, Default)]
+pub struct SlotSave {
+    pub locations: Locations,
+    pub items: Items,
+    /// Index of the next item expected from `ReceivedItems`
+    pub items_index: i32,
+    /// `None` for saves from before it was kept
+    pub slot_data: Option<CleanedSlotData>,
+    /// The last messages shown, oldest first
+    pub messages: Vec<PrintJSON>,
+    pub multi_send: bool,
+}
+
+impl SlotSave {
+    /// Adds the progress of a save of the same slot made elsewhere. Checks are combined and the items of whichever save
+    /// got further are kept, while messages and settings stay as they are
+    pub fn merge(&mut self, other: &SlotSave) {
+        self.locations.merge(&other.locations);
+        if other.items_index > self.items_index {
+            self.items = other.items;
+            self.items_index = other.items_index;
+        }
+        self.slot_data = self.slot_data.or(other.slot_data);
+    }
+}
+
+/// Version of the save format `encode` writes. Only changes that older versions can't read past need a new version,
+/// new fields are ignored by them and default when missing
+pub const SAVE_VERSION: u32 = 1;
+
+/// How many previous saves are kept, as `<name>.1` for the newest up to `<name>.SAVE_BACKUPS`
+pub const SAVE_BACKUPS: usize = 3;
+
+/// The default for `DefaultPersistentStore::with_backup_interval`
+pub const BACKUP_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+pub fn backup_name(name: &str, n: usize) -> String {
+    format!("{name}.{n}")
+}
+
+/// When a store turns the save it's about to replace into a backup. Saving happens after every item and check, so
+/// backing up each one would only keep the last few seconds. The first save of a session is always backed up, after
+/// that one is made once the newest backup is `interval` old
+pub struct BackupSchedule {
+    interval: Duration,
+    /// Whether this session's first save has been made
+    backed_up: Cell<bool>,
+}
+
+impl BackupSchedule {
+    pub fn new(interval: Duration) -> Self {
+        BackupSchedule {
+            interval,
+            backed_up: Cell::new(false),
+        }
+    }
+
+    /// Whether the current save should be backed up, given the age of the newest backup if there is one
+    pub fn due(&self, newest_backup_age: Option<Duration>) -> bool {
+        !self.backed_up.get() || newest_backup_age.is_none_or(|age| age >= self.interval)
+    }
+
+    /// Moves every backup of `name` one along with `shift(from, to)`, dropping the oldest, so `<name>.1` is free for the
+    /// current save
+    pub fn rotate(&self, name: &str, mut shift: impl FnMut(&str, &str)) {
+        for n in (1..SAVE_BACKUPS).rev() {
+            shift(&backup_name(name, n), &backup_name(name, n + 1));
+        }
+    }
+
+    /// Records that a save was made, backed up or not
+    pub fn saved(&self) {
+        self.backed_up.set(true);
+    }
+}
+
+impl Default for BackupSchedule {
+    fn default() -> Self {
+        BackupSchedule::new(BACKUP_INTERVAL)
+    }
+}
+
+const DIFFICULTIES: [&str; 4] = ["Normal", "Advanced", "Challenge", "Ultimate"];
+
+/// The save format, which names everything by its display name so adding content never changes what a save means.
+/// Names that are no longer known are skipped when loading
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct SaveFile {
+    version: u32,
+    items_index: i32,
+    victory: bool,
+    /// Checked difficulties by villain
+    villains: BTreeMap<String, Vec<String>>,
+    team_villains: BTreeMap<String, Vec<String>>,
+    variants: Vec<String>,
+    environments: Vec<String>,
+    /// Received items by name, besides scions
+    items: Vec<String>,
+    scions: u32,
+    slot_data: Option<CleanedSlotData>,
+    messages: Vec<PrintJSON>,
+    multi_send: bool,
+}
+
+fn item_names(items: &Items) -> Vec<String> {
+    let villains = Villain::iter().filter(|v| items.has_villain(*v)).map(Item::Villain);
+    let team_villains = TeamVillain::iter().filter(|v| items.has_team_villain(*v)).map(Item::TeamVillain);
+    let heroes = Hero::iter().filter(|h| items.has_base_hero(*h)).map(Item::Hero);
+    let variants = Variant::iter().filter(|v| items.has_hero_variant(*v)).map(Item::Variant);
+    let environments = Environment::iter().filter(|e| items.has_environment(*e)).map(Item::Environment);
+
+    villains
+        .chain(team_villains)
+        .chain(heroes)
+        .chain(variants)
+        .chain(environments)
+        .map(|item| item.as_str().to_string())
+        .collect()
+}
+
+fn difficulty_names(checked: u8) -> Vec<String> {
+    (0..DIFFICULTIES.len()).filter(|d| checked & 1 << d > 0).map(|d| DIFFICULTIES[d].to_string()).collect()
+}
+
+fn difficulties(names: &[String]) -> impl Iterator<Item = u8> + '_ {
+    names.iter().filter_map(|name| DIFFICULTIES.iter().position(|d| d == name)).map(|d| d as u8)
+}
+
+fn to_file(save: &SlotSave) -> SaveFile {
+    let locations = &save.locations;
+    SaveFile {
+        version: SAVE_VERSION,
+        items_index: save.items_index,
+        victory: locations.victory,
+        villains: Villain::iter()
+            .filter(|v| locations.villains[*v as usize] > 0)
+            .map(|v| (v.as_str().to_string(), difficulty_names(locations.villains[v as usize])))
+            .collect(),
+        team_villains: TeamVillain::iter()
+            .filter(|v| locations.team_villains[*v as usize] > 0)
+            .map(|v| (v.as_str().to_string(), difficulty_names(locations.team_villains[v as usize])))
+            .collect(),
+        // Only variants with an unlock have a location, the rest never count as unchecked
+        variants: Variant::iter()
+            .filter(|v| !v.as_desc().is_empty() && !locations.has_unchecked_variant(*v))
+            .map(|v| v.as_str().to_string())
+            .collect(),
+        environments: Environment::iter().filter(|e| !locations.has_unchecked_environment(*e)).map(|e| e.as_str().to_string()).collect(),
+        items: item_names(&save.items),
+        scions: save.items.scions,
+        slot_data: save.slot_data,
+        messages: save.messages.clone(),
+        multi_send: save.multi_send,
+    }
+}
+
+pub fn encode(save: &SlotSave) -> Vec<u8> {
+    to_vec_pretty(&to_file(save)).expect("saves always serialize")
+}
+
+/// Decodes a save written by `encode`, or by older versions in the binary layout from before saves were versioned.
+/// Saves from a newer version aren't understood and give `None`
+pub fn decode(buf: &[u8]) -> Option<SlotSave> {
+    match from_slice::<SaveFile>(buf) {
+        Ok(file) => from_file(file),
+        Err(_) => decode_legacy(buf),
+    }
+}
+
+/// Decodes a save kept in data storage, which is in the format `encode` writes
+pub fn decode_value(value: &Value) -> Option<SlotSave> {
+    SaveFile::deserialize(value).ok().and_then(from_file)
+}
+
+fn from_file(file: SaveFile) -> Option<SlotSave> {
+    if file.version == 0 || file.version > SAVE_VERSION {
+        return None;
+    }
+
+    let mut locations = Locations::new();
+    locations.victory = file.victory;
+    for villain in Villain::iter() {
+        for d in difficulties(file.villains.get(villain.as_str()).map_or(&[], Vec::as_slice)) {
+            locations.mark_villain(villain, d);
+        }
+    }
+    for villain in TeamVillain::iter() {
+        for d in difficulties(file.team_villains.get(villain.as_str()).map_or(&[], Vec::as_slice)) {
+            locations.mark_team_villain(villain, d);
+        }
+    }
+    for variant in Variant::iter().filter(|v| file.variants.iter().any(|name| name == v.as_str())) {
+        locations.mark_variant(variant);
+    }
+    for environment in Environment::iter().filter(|e| file.environments.iter().any(|name| name == e.as_str())) {
+        locations.mark_environment(environment);
+    }
+
+    let mut items = Items::new();
+    for item in file.items.iter().filter_map(|name| Item::from_str(name)).filter(|item| *item != Item::Scion) {
+        items.set_item(item);
+    }
+    items.scions = file.scions;
+
+    Some(SlotSave {
+        locations,
+        items,
+        items_index: file.items_index,
+        slot_data: file.slot_data,
+        messages: file.messages,
+        multi_send: file.multi_send,
+    })
+}
+
+const LOCATIONS_LEN: usize = 1 + Villain::variant_count() + TeamVillain::variant_count() + 16 + 8;
+
+/// The binary layout saves had before they were versioned, indexed by enum ordinals. Only readable as long as no
+/// villains were added since it was written. Saves from before the item index was stored are accepted with an index of 0
+fn decode_legacy(buf: &[u8]) -> Option<SlotSave> {
+    if buf.len() != LOCATIONS_LEN && buf.len() != LOCATIONS_LEN + 4 {
+        return None;
+    }
+
+    let mut locations = Locations::new();
+    locations.victory = buf[0] > 0;
+    let mut start = 1;
+    locations.villains.copy_from_slice(&buf[start..start + Villain::variant_count()]);
+    start += Villain::variant_count();
+    locations.team_villains.copy_from_slice(&buf[start..start + TeamVillain::variant_count()]);
+    start += TeamVillain::variant_count();
+    locations.variants = u128::from_le_bytes(buf[start..start + 16].try_into().unwrap());
+    start += 16;
+    locations.environments = u64::from_le_bytes(buf[start..start + 8].try_into().unwrap());
+    start += 8;
+
+    let items_index = if buf.len() > start { i32::from_le_bytes(buf[start..start + 4].try_into().unwrap()) } else { 0 };
+
+    Some(SlotSave {
+        locations,
+        items_index,
+        ..SlotSave::default()
+    })
+}
+
+/// Saves to `<dir>/<key name>`, `./persistent` unless made with `in_dir`, with the previous saves kept next to it
 pub struct DefaultPersistentStore {
-    seed: String,
+    dir: PathBuf,
+    fallback: Option<PathBuf>,
+    key: SaveKey,
+    backups: BackupSchedule,
+    adopt_seed_save: bool,
 }
 
-impl PersistentStore for DefaultPersistentStore {
-    fn new(seed: &str) -> Self {
-        DefaultPersistentStore { seed: seed.to_string() }
-    }
-
-    fn load(&self) -> Locations {
-        if let Ok(mut reader) = File::open(Path::new("./persistent").join(&self.seed)) {
-            let mut buf = vec![];
-            if let Ok(len) = reader.read_to_end(&mut buf) {
-                if len != 1 + Villain::variant_count() + TeamVillain::variant_count() + 16 + 8 {
-                    println!("Save file is invalid. Was it made with an older version?");
-                    let _ = rename(Path::new("./persistent").join(&self.seed), Path::new("./persistent").join(format!("{}-backup", self.seed)));
-                    return Locations::new();
-                } else {
-                    let mut locations = Locations::new();
-                    locations.victory = buf[0] > 0;
-                    let mut start = 1;
-                    locations.villains.copy_from_slice(&buf[start..start + Villain::variant_count()]);
-                    start += Villain::variant_count();
-                    locations.team_villains.copy_from_slice(&buf[start..start + TeamVillain::variant_count()]);
-                    start += TeamVillain::variant_count();
-                    locations.variants = u128::from_le_bytes(buf[start..start + 16].try_into().unwrap());
-                    start += 16;
-                    locations.environments = u64::from_le_bytes(buf[start..start + 8].try_into().unwrap());
-                    return locations;
-                }
-            }
+impl DefaultPersistentStore {
+    pub fn in_dir(dir: impl Into<PathBuf>, key: &SaveKey) -> Self {
+        DefaultPersistentStore {
+            dir: dir.into(),
+            fallback: None,
+            key: key.clone(),
+            backups: BackupSchedule::default(),
+            adopt_seed_save: false,
         }
+    }
+
+    /// How long after the newest backup the next one is made, see `BackupSchedule`
+    pub fn with_backup_interval(mut self, backup_interval: Duration) -> Self {
+        self.backups = BackupSchedule::new(backup_interval);
+        self
+    }
 
-        Locations::new()
+    /// Whether a save named after the seed alone, from before saves were kept per slot, is taken over when the slot has
+    /// none of its own. Only safe when it can't belong to another slot, see `sole_slot_of_game`
+    pub fn adopt_seed_save(mut self, adopt: bool) -> Self {
+        self.adopt_seed_save = adopt;
+        self
     }
 
-    fn save(&self, locations: &Locations) {
-        if let Err(err) = create_dir_all("./persistent") {
-            println!("Failed to create persistent storage with error {err}");
+    /// Also looks for seed-only saves in `dir`, e.g. where saves used to be kept. Saving always goes to the store's own
+    /// dir
+    pub fn with_fallback(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.fallback = Some(dir.into());
+        self
+    }
+
+    fn load_from(&self, dir: &Path, name: &str) -> Option<SlotSave> {
+        let path = dir.join(name);
+        if !path.exists() {
+            return None;
+        }
+
+        if let Some(save) = read(&path).ok().and_then(|buf| decode(&buf)) {
+            return Some(save);
         }
 
-        match File::create(Path::new("./persistent").join(&self.seed)) {
-            Ok(mut writer) => {
-                let mut buf = vec![if locations.victory { 1 } else { 0 }];
-                buf.extend(locations.villains);
-                buf.extend(locations.team_villains);
-                buf.extend(locations.variants.to_le_bytes());
-                buf.extend(locations.environments.to_le_bytes());
-                if let Err(err) = writer.write_all(&buf) {
-                    println!("Failed to save locations to persistent storage with error {err}");
-                }
+        // Kept out of the way so saving doesn't rotate it into the backups, then the newest backup that's fine is used
+        println!("Save file is unreadable or from a newer version");
+        let _ = rename(&path, dir.join(format!("{name}-backup")));
+        (1..=SAVE_BACKUPS).find_map(|n| read(dir.join(backup_name(name, n))).ok().and_then(|buf| decode(&buf)))
+    }
+
+    // Saved under the slot's name and the seed-only save renamed, so it's only adopted once
+    fn adopt_from(&self, dir: &Path) -> Option<SlotSave> {
+        let save = self.load_from(dir, &self.key.seed)?;
+        match self.write(&save) {
+            Ok(()) => {
+                let _ = rename(dir.join(&self.key.seed), dir.join(adopted_name(&self.key.seed)));
             }
             Err(err) => println!("Failed to save locations to persistent storage with error {err}"),
         }
+        Some(save)
+    }
+
+    fn backup_due(&self, name: &str) -> bool {
+        let age = metadata(self.dir.join(backup_name(name, 1)))
+            .and_then(|backup| backup.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok());
+        self.backups.due(age)
+    }
+
+    // Written to a temporary file and renamed over the save, so a crash mid-write leaves the previous save intact
+    fn write(&self, save: &SlotSave) -> io::Result<()> {
+        create_dir_all(&self.dir)?;
+        let name = self.key.name();
+        let temp = self.dir.join(format!("{name}.tmp"));
+
+        let res = self.replace(&name, &temp, save);
+        if res.is_err() {
+            let _ = remove_file(&temp);
+        }
+        res
+    }
+
+    fn replace(&self, name: &str, temp: &Path, save: &SlotSave) -> io::Result<()> {
+        let mut file = File::create(temp)?;
+        file.write_all(&encode(save))?;
+        file.sync_all()?;
+
+        let path = self.dir.join(name);
+        if path.exists() && self.backup_due(name) {
+            self.backups.rotate(name, |from, to| {
+                let _ = rename(self.dir.join(from), self.dir.join(to));
+            });
+            copy(&path, self.dir.join(backup_name(name, 1)))?;
+        }
+        self.backups.saved();
+        rename(temp, &path)
+    }
+}
+
+impl PersistentStore for DefaultPersistentStore {
+    fn new(key: &SaveKey) -> Self {
+        DefaultPersistentStore::in_dir("./persistent", key)
+    }
+
+    fn load(&self) -> SlotSave {
+        if let Some(save) = self.load_from(&self.dir, &self.key.name()) {
+            return save;
+        }
+        if !self.adopt_seed_save {
+            return SlotSave::default();
+        }
+
+        self.adopt_from(&self.dir).or_else(|| self.fallback.as_ref().and_then(|dir| self.adopt_from(dir))).unwrap_or_default()
+    }
+
+    fn save(&self, save: &SlotSave) {
+        if let Err(err) = self.write(save) {
+            println!("Failed to save locations to persistent storage with error {err}");
+        }
+    }
+}
+
+/// Keeps saves in the server's data storage as well as in a local store, so they're shared by every device playing the
+/// slot. Without a connection to send saves through, see `send_to`, it only saves locally.
+///
+/// The store doesn't talk to the server itself. What's stored under `storage_key` is handed to `receive`, from a
+/// `Retrieved` before loading and from the `SetReply`s of a `SetNotify` while playing
+pub struct DataStoragePersistentStore<P: PersistentStore> {
+    local: P,
+    key: String,
+    remote: Option<SlotSave>,
+    sink: Option<Box<dyn Fn(Set) + Send>>,
+}
+
+impl<P: PersistentStore> DataStoragePersistentStore<P> {
+    pub fn with_local(local: P, key: &SaveKey) -> Self {
+        DataStoragePersistentStore {
+            local,
+            key: key.storage_key(),
+            remote: None,
+            sink: None,
+        }
+    }
+
+    /// From now on every save is also handed to `sink`, as a `Set` replacing the stored save
+    pub fn send_to(&mut self, sink: impl Fn(Set) + Send + 'static) {
+        self.sink = Some(Box::new(sink));
+    }
+
+    pub fn storage_key(&self) -> &str {
+        &self.key
+    }
+
+    /// Saves without sending, for when what's stored under `storage_key` already has everything `save` does
+    pub fn save_local(&self, save: &SlotSave) {
+        self.local.save(save);
+    }
+
+    /// Takes the value stored under `storage_key`, which `load` adds to the local save. Returns the save, or `None` if
+    /// nothing is stored yet or the value isn't a save this version understands
+    pub fn receive(&mut self, value: &Value) -> Option<SlotSave> {
+        let save = decode_value(value)?;
+        self.remote = Some(save.clone());
+        Some(save)
+    }
+}
+
+impl<P: PersistentStore> PersistentStore for DataStoragePersistentStore<P> {
+    fn new(key: &SaveKey) -> Self {
+        DataStoragePersistentStore::with_local(P::new(key), key)
+    }
+
+    fn load(&self) -> SlotSave {
+        let mut save = self.local.load();
+        if let Some(remote) = &self.remote {
+            save.merge(remote);
+        }
+        save
+    }
+
+    fn save(&self, save: &SlotSave) {
+        self.local.save(save);
+        if let Some(sink) = &self.sink {
+            sink(Set {
+                key: self.key.clone(),
+                default: Value::Null,
+                want_reply: false,
+                operations: vec![DataStorageOperation::Replace(to_value(to_file(save)).expect("saves always serialize"))],
+                extra: serde_json::Map::new(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data::Hero,
+        test_support::{collect_sets, key, MemoryStore, TempDir},
+    };
+    use serde_json::{from_value, json};
+    use std::fs;
+
+    fn example() -> SlotSave {
+        let mut locations = Locations::new();
+        locations.victory = true;
+        locations.mark_villain(Villain::BaronBlade, 0);
+        locations.mark_villain(Villain::BaronBlade, 3);
+        locations.mark_team_villain(TeamVillain::BaronBlade, 1);
+        locations.mark_variant(Variant::DarkWatchSetback);
+        locations.mark_environment(Environment::Megalopolis);
+        SlotSave {
+            locations,
+            items_index: 12,
+            ..SlotSave::default()
+        }
+    }
+
+    // The layout saves were written in before they were versioned
+    fn legacy(save: &SlotSave) -> Vec<u8> {
+        let mut buf = vec![u8::from(save.locations.victory)];
+        buf.extend(save.locations.villains);
+        buf.extend(save.locations.team_villains);
+        buf.extend(save.locations.variants.to_le_bytes());
+        buf.extend(save.locations.environments.to_le_bytes());
+        buf.extend(save.items_index.to_le_bytes());
+        buf
+    }
+
+    fn assert_same(a: &SlotSave, b: &SlotSave) {
+        assert_eq!(encode(a), encode(b));
+        assert_eq!(a.items_index, b.items_index);
+    }
+
+    #[test]
+    fn saves_name_what_was_checked() {
+        let save: Value = serde_json::from_slice(&encode(&example())).unwrap();
+        assert_eq!(save["version"], SAVE_VERSION);
+        assert_eq!(save["items_index"], 12);
+        assert_eq!(save["villains"], json!({"Baron Blade": ["Normal", "Ultimate"]}));
+        assert_eq!(save["environments"], json!(["Megalopolis"]));
+        assert_eq!(save["variants"], json!([Variant::DarkWatchSetback.as_str()]));
+
+        assert_same(&decode(&encode(&example())).unwrap(), &example());
+    }
+
+    #[test]
+    fn legacy_saves_are_migrated() {
+        let migrated = decode(&legacy(&example())).unwrap();
+        assert_same(&migrated, &example());
+
+        // From before the item index was saved
+        let mut buf = legacy(&example());
+        buf.truncate(buf.len() - 4);
+        assert_eq!(decode(&buf).unwrap().items_index, 0);
+    }
+
+    #[test]
+    fn unknown_names_are_skipped() {
+        let save = json!({
+            "version": 1,
+            "items_index": 2,
+            "villains": {"Baron Blade": ["Advanced", "Impossible"], "Not a villain": ["Normal"]},
+            "environments": ["Megalopolis", "Nowhere"],
+            "some_later_field": true
+        });
+        let save = decode(save.to_string().as_bytes()).unwrap();
+        assert_eq!(save.items_index, 2);
+        assert!(!save.locations.has_unchecked_villain(Villain::BaronBlade, 1));
+        assert!(save.locations.has_unchecked_villain(Villain::BaronBlade, 0));
+        assert!(!save.locations.has_unchecked_environment(Environment::Megalopolis));
+    }
+
+    #[test]
+    fn invalid_and_newer_saves_are_rejected() {
+        assert!(decode(b"").is_none());
+        assert!(decode(b"{\"version\": 1").is_none());
+        assert!(decode(json!({"version": SAVE_VERSION + 1}).to_string().as_bytes()).is_none());
+        assert!(decode(json!({"items_index": 1}).to_string().as_bytes()).is_none());
+    }
+
+    #[test]
+    fn previous_saves_are_rotated() {
+        let temp = TempDir::new("rotation");
+        let dir = temp.path();
+        let key = key(1);
+        let store = DefaultPersistentStore::in_dir(dir, &key).with_backup_interval(Duration::ZERO);
+
+        for items_index in 1..=5 {
+            store.save(&SlotSave { items_index, ..SlotSave::default() });
+        }
+        let mut files: Vec<_> = fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).collect();
+        files.sort();
+        assert_eq!(files, ["12345-0-1", "12345-0-1.1", "12345-0-1.2", "12345-0-1.3"]);
+        assert_eq!(SAVE_BACKUPS, 3);
+        for n in 1..=SAVE_BACKUPS {
+            assert_eq!(decode(&fs::read(dir.join(backup_name(&key.name(), n))).unwrap()).unwrap().items_index, 5 - n as i32);
+        }
+        assert_eq!(store.load().items_index, 5);
+
+        // A broken save is moved aside and the newest backup used instead
+        fs::write(dir.join(key.name()), "{\"version\": 1, \"items_ind").unwrap();
+        assert_eq!(store.load().items_index, 4);
+        assert!(dir.join("12345-0-1-backup").exists());
+    }
+
+    #[test]
+    fn backups_are_spaced_out() {
+        let temp = TempDir::new("backup-interval");
+        let dir = temp.path();
+        let key = key(1);
+
+        let store = DefaultPersistentStore::in_dir(dir, &key);
+        for items_index in 1..=5 {
+            store.save(&SlotSave { items_index, ..SlotSave::default() });
+        }
+        let backups = || {
+            (1..=SAVE_BACKUPS)
+                .map_while(|n| fs::read(dir.join(backup_name(&key.name(), n))).ok())
+                .map(|buf| decode(&buf).unwrap().items_index)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(backups(), [1]);
+
+        // The next session starts with a backup of where the last one left off
+        let store = DefaultPersistentStore::in_dir(dir, &key);
+        store.save(&SlotSave {
+            items_index: 6,
+            ..SlotSave::default()
+        });
+        assert_eq!(backups(), [5, 1]);
+
+        // Backups that can't be made fail the save without leaving the temporary file behind
+        for n in 1..=SAVE_BACKUPS {
+            let backup = dir.join(backup_name(&key.name(), n));
+            let _ = fs::remove_file(&backup);
+            fs::create_dir_all(backup.join("blocked")).unwrap();
+        }
+        DefaultPersistentStore::in_dir(dir, &key).save(&SlotSave {
+            items_index: 7,
+            ..SlotSave::default()
+        });
+        assert!(!dir.join(format!("{}.tmp", key.name())).exists());
+        assert_eq!(decode(&fs::read(dir.join(key.name())).unwrap()).unwrap().items_index, 6);
+    }
+
+    #[test]
+    fn legacy_saves_need_a_sole_slot_of_the_game() {
+        let slot = |game: &str, r#type| NetworkSlot {
+            name: "Player".into(),
+            game: game.into(),
+            r#type,
+            group_members: Vec::new(),
+        };
+        let mut slot_info = HashMap::from([("1".into(), slot(GAME, SlotType::Player)), ("2".into(), slot("Other", SlotType::Player))]);
+        assert!(sole_slot_of_game(&slot_info));
+
+        // Groups and spectators have no save of their own
+        slot_info.insert("3".into(), slot(GAME, SlotType::Group));
+        slot_info.insert("4".into(), slot(GAME, SlotType::Spectator));
+        assert!(sole_slot_of_game(&slot_info));
+
+        slot_info.insert("5".into(), slot(GAME, SlotType::Player));
+        assert!(!sole_slot_of_game(&slot_info));
+    }
+
+    #[test]
+    fn seed_only_saves_are_adopted_when_allowed() {
+        let temp = TempDir::new("seed-only");
+        let dir = temp.path();
+        fs::create_dir_all(dir).unwrap();
+        let old = SlotSave {
+            items_index: 7,
+            ..SlotSave::default()
+        };
+        fs::write(dir.join("12345"), encode(&old)).unwrap();
+
+        // It could be either slot's, so it's left alone
+        assert_eq!(DefaultPersistentStore::in_dir(dir, &key(1)).load().items_index, 0);
+        assert!(dir.join("12345").exists());
+        assert!(!dir.join(key(1).name()).exists());
+
+        let store = DefaultPersistentStore::in_dir(dir, &key(1)).adopt_seed_save(true);
+        assert_eq!(store.load().items_index, 7);
+        assert!(!dir.join("12345").exists());
+        assert!(dir.join(adopted_name("12345")).exists());
+        assert_eq!(decode(&fs::read(dir.join(key(1).name())).unwrap()).unwrap().items_index, 7);
+
+        store.save(&SlotSave { items_index: 8, ..old.clone() });
+        assert_eq!(store.load().items_index, 8);
+        assert_eq!(DefaultPersistentStore::in_dir(dir, &key(2)).adopt_seed_save(true).load().items_index, 0);
+    }
+
+    fn snapshot() -> SlotSave {
+        let mut items = Items::new();
+        items.set_item(Item::Villain(Villain::BaronBlade));
+        items.set_item(Item::TeamVillain(TeamVillain::BaronBlade));
+        items.set_item(Item::Hero(Hero::Bunker));
+        items.set_item(Item::Variant(Variant::DarkWatchSetback));
+        items.set_item(Item::Environment(Environment::Megalopolis));
+        items.scions = 2;
+
+        let msg: PrintJSON = from_value(json!({"data": [{"text": "Hello"}]})).unwrap();
+        SlotSave {
+            items,
+            slot_data: Some(CleanedSlotData {
+                required_scions: 3,
+                required_villains: 4,
+                required_variants: 0,
+                villain_difficulty_points: [1, 2, 3, 4],
+                locations_per: [1, 1, 1, 1, 2, 1],
+            }),
+            messages: vec![msg],
+            multi_send: true,
+            ..example()
+        }
+    }
+
+    #[test]
+    fn saves_keep_the_whole_slot_state() {
+        let save = decode(&encode(&snapshot())).unwrap();
+        assert_same(&save, &snapshot());
+        assert!(save.items.has_villain(Villain::BaronBlade));
+        assert!(save.items.has_team_villain(TeamVillain::BaronBlade));
+        assert!(save.items.has_base_hero(Hero::Bunker));
+        assert!(save.items.has_hero_variant(Variant::DarkWatchSetback));
+        assert!(save.items.has_environment(Environment::Megalopolis));
+        assert!(!save.items.has_villain(Villain::Omnitron));
+        assert_eq!(save.items.scions, 2);
+        assert_eq!(save.slot_data, snapshot().slot_data);
+        assert!(save.multi_send);
+        assert_eq!(save.messages.len(), 1);
+        assert_eq!(save.messages[0].data[0].text.as_deref(), Some("Hello"));
+    }
+
+    #[test]
+    fn saves_from_before_the_snapshot_still_load() {
+        let save = json!({"version": 1, "items_index": 3, "environments": ["Megalopolis"]});
+        let save = decode(save.to_string().as_bytes()).unwrap();
+        assert_eq!(save.items_index, 3);
+        assert!(!save.items.has_environment(Environment::Megalopolis));
+        assert_eq!(save.items.scions, 0);
+        assert!(save.slot_data.is_none());
+        assert!(save.messages.is_empty());
+        assert!(!save.multi_send);
+    }
+
+    fn stored_value(set: &Set) -> &Value {
+        match set.operations.as_slice() {
+            [DataStorageOperation::Replace(value)] => value,
+            operations => panic!("expected a single replace, got {operations:?}"),
+        }
+    }
+
+    #[test]
+    fn shared_saves_combine_checks() {
+        let key = key(1);
+        assert_eq!(key.storage_key(), "sotm_ap_save_0_1");
+
+        let mut here = DataStoragePersistentStore::<MemoryStore>::new(&key);
+        let sets = collect_sets(&mut here);
+        here.save(&example());
+        let sets = sets.lock().unwrap();
+        assert_eq!(sets.len(), 1);
+        assert_eq!(sets[0].key, key.storage_key());
+        assert_same(&decode_value(stored_value(&sets[0])).unwrap(), &example());
+
+        // Another device with checks of its own and more items
+        let mut elsewhere = DataStoragePersistentStore::<MemoryStore>::new(&key);
+        let mut own = SlotSave {
+            items_index: 20,
+            multi_send: true,
+            ..SlotSave::default()
+        };
+        own.locations.mark_villain(Villain::Omnitron, 2);
+        elsewhere.save(&own);
+        assert!(elsewhere.receive(&Value::Null).is_none());
+        assert!(elsewhere.receive(stored_value(&sets[0])).is_some());
+
+        let loaded = elsewhere.load();
+        let mut locations = example().locations;
+        locations.mark_villain(Villain::Omnitron, 2);
+        assert_eq!(loaded.locations, locations);
+        assert_eq!(loaded.items_index, 20);
+        assert!(loaded.multi_send);
+    }
+
+    #[test]
+    fn saves_fall_back_to_the_old_location() {
+        let temp = TempDir::new("fallback");
+        let root = temp.path();
+        let legacy = root.join("legacy");
+        let current = root.join("current");
+
+        let save = SlotSave {
+            items_index: 5,
+            ..SlotSave::default()
+        };
+        fs::create_dir_all(&legacy).unwrap();
+        fs::write(legacy.join("12345"), encode(&save)).unwrap();
+
+        let store = DefaultPersistentStore::in_dir(&current, &key(1)).with_fallback(&legacy).adopt_seed_save(true);
+        assert_eq!(store.load().items_index, 5);
+        assert!(current.join(key(1).name()).exists());
+        assert_eq!(decode(&fs::read(legacy.join(adopted_name("12345"))).unwrap()).unwrap().items_index, 5);
+
+        // Once saved in the new place, that's the one used
+        store.save(&SlotSave { items_index: 6, ..save.clone() });
+        assert_eq!(store.load().items_index, 6);
     }
 }
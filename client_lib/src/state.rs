@@ -3,6 +3,7 @@ use crate::{
     logic::can_unlock,
 };
 use archipelago_protocol::SlotData;
+use serde::{Deserialize, Serialize};
 use strum::IntoEnumIterator;
 
 #[derive(Debug, Clone, Copy)]
@@ -12,7 +13,7 @@ pub struct State {
     pub slot_data: CleanedSlotData,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct CleanedSlotData {
     pub required_scions: u32,
     pub required_villains: u32,
@@ -30,7 +31,7 @@ pub struct Items {
     pub environments: u64,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Locations {
     pub victory: bool,
     pub villains: [u8; Villain::variant_count()],
@@ -237,6 +238,16 @@ impl Locations {
         self.environments & 1 << environment as u64 == 0
     }
 
+    pub fn is_checked(&self, location: Location) -> bool {
+        match location {
+            Location::Variant(v) => !self.has_unchecked_variant(v),
+            Location::Villain((v, d)) => !self.has_unchecked_villain(v, d),
+            Location::TeamVillain((v, d)) => !self.has_unchecked_team_villain(v, d),
+            Location::Environment(e) => !self.has_unchecked_environment(e),
+            Location::Victory => self.victory,
+        }
+    }
+
     pub fn mark_villain(&mut self, villain: Villain, difficulty: u8) {
         self.villains[villain as usize] |= 1 << difficulty;
     }
@@ -265,6 +276,48 @@ impl Locations {
             Location::Victory => (),
         }
     }
+
+    /// Marks everything that is checked in `other` as well
+    pub fn merge(&mut self, other: &Locations) {
+        self.victory |= other.victory;
+        for (checked, other) in self.villains.iter_mut().zip(other.villains) {
+            *checked |= other;
+        }
+        for (checked, other) in self.team_villains.iter_mut().zip(other.team_villains) {
+            *checked |= other;
+        }
+        self.variants |= other.variants;
+        self.environments |= other.environments;
+    }
+
+    pub fn unmark_villain(&mut self, villain: Villain, difficulty: u8) {
+        self.villains[villain as usize] &= !(1 << difficulty);
+    }
+
+    pub fn unmark_team_villain(&mut self, team_villain: TeamVillain, difficulty: u8) {
+        self.team_villains[team_villain as usize] &= !(1 << difficulty);
+    }
+
+    pub fn unmark_variant(&mut self, variant: Variant) {
+        if variant as usize >= Variant::BaccaratAceOfSwords as usize {
+            return;
+        }
+        self.variants &= !(1 << variant as u128);
+    }
+
+    pub fn unmark_environment(&mut self, environment: Environment) {
+        self.environments &= !(1 << environment as u64);
+    }
+
+    pub fn unmark_location(&mut self, location: Location) {
+        match location {
+            Location::Variant(v) => self.unmark_variant(v),
+            Location::Villain((v, d)) => self.unmark_villain(v, d),
+            Location::TeamVillain((v, d)) => self.unmark_team_villain(v, d),
+            Location::Environment(e) => self.unmark_environment(e),
+            Location::Victory => (),
+        }
+    }
 }
 
 impl Default for Locations {
@@ -0,0 +1,70 @@
+use crate::persistent::{DataStoragePersistentStore, PersistentStore, SaveKey, SlotSave};
+use archipelago_protocol::Set;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+    sync::{Arc, Mutex},
+};
+
+/// An empty directory for a test, removed again when dropped, also when the test fails
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let dir = env::temp_dir().join(format!("archipelago-{}-{name}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        TempDir(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// The key of `slot` on the first team of seed `12345`
+pub fn key(slot: i32) -> SaveKey {
+    SaveKey {
+        seed: String::from("12345"),
+        team: 0,
+        slot,
+    }
+}
+
+/// Keeps the last save in memory and loads it back, starting out empty
+#[derive(Default)]
+pub struct MemoryStore(Mutex<Option<SlotSave>>);
+
+impl MemoryStore {
+    pub fn last_save(&self) -> Option<SlotSave> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl PersistentStore for MemoryStore {
+    fn new(_key: &SaveKey) -> Self {
+        MemoryStore::default()
+    }
+
+    fn load(&self) -> SlotSave {
+        self.last_save().unwrap_or_default()
+    }
+
+    fn save(&self, save: &SlotSave) {
+        *self.0.lock().unwrap() = Some(save.clone());
+    }
+}
+
+/// Every `Set` that `store` sends from now on
+pub fn collect_sets<P: PersistentStore>(store: &mut DataStoragePersistentStore<P>) -> Arc<Mutex<Vec<Set>>> {
+    let sets = Arc::new(Mutex::new(vec![]));
+    let sink = sets.clone();
+    store.send_to(move |set| sink.lock().unwrap().push(set));
+    sets
+}
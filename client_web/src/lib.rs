@@ -1,29 +1,43 @@
 mod datapackage;
 mod format_json;
 mod persistent;
+mod websocket;
 mod wrap_state;
 
-use archipelago_protocol::{Connected, RoomInfo};
-use client_lib::{data::Location, datapackage::DatapackageStore, persistent::PersistentStore, Session};
-use datapackage::WebDatapackageStore;
+use archipelago_protocol::{Connected, DataPackageObject, RoomInfo, RoomUpdate};
+use client_lib::{
+    data::Location,
+    datapackage::DatapackageStore,
+    persistent::{sole_slot_of_game, PersistentStore, SaveKey},
+    ItemSync, Session,
+};
+use datapackage::{Store, WebDatapackageStore};
 use format_json::format;
 use persistent::WebPersistentStore;
 use serde_json::from_str;
 use wasm_bindgen::prelude::wasm_bindgen;
+pub use websocket::WebSocketTransport;
 use wrap_state::{wrap_state, WasmLocation, WasmState};
 
 #[wasm_bindgen]
 pub struct WasmSession {
-    inner: Session<WebDatapackageStore, WebPersistentStore>,
+    inner: Session<Store, WebPersistentStore>,
 }
 
 #[wasm_bindgen]
-pub fn new_session(mut datapackage_store: WebDatapackageStore, room_info: &str, connected: &str, slot: &str) -> WasmSession {
+pub fn new_session(datapackage_store: WebDatapackageStore, room_info: &str, connected: &str, slot: &str) -> WasmSession {
     if let (Ok(room_info), Ok(connected)) = (from_str::<RoomInfo>(room_info), from_str::<Connected>(connected)) {
+        let mut datapackage_store = datapackage_store.0;
         datapackage_store.build_player_map(&connected);
 
+        let key = SaveKey {
+            seed: room_info.seed_name,
+            team: connected.team,
+            slot: connected.slot,
+        };
+        let persistent_store = WebPersistentStore::new(&key).adopt_seed_save(sole_slot_of_game(&connected.slot_info));
         WasmSession {
-            inner: Session::new(&room_info.seed_name, datapackage_store, connected, slot),
+            inner: Session::with_stores(datapackage_store, persistent_store, connected, slot),
         }
     } else {
         panic!("Failed to parse session info");
@@ -40,44 +54,60 @@ impl WasmSession {
         }
     }
 
-    pub fn get_location_ids(&mut self, locations: Vec<WasmLocation>) -> Vec<i32> {
-        let mut location_ids = vec![];
-
-        for location in locations.into_iter().map(|l| l.into_inner()) {
-            self.inner.state.checked_locations.mark_location(location);
+    /// Games that need fetching with a `GetDataPackage` before this message can be formatted properly
+    pub fn report_misses(&mut self, json: &str) -> Vec<String> {
+        if let Ok(msg) = from_str(json) {
+            self.inner.datapackage_store.report_misses(&msg)
+        } else {
+            vec![]
+        }
+    }
 
-            for n in 0..=self.inner.state.slot_data.locations_per[match location {
-                Location::Variant(_) => 5,
-                Location::Villain((_, d)) | Location::TeamVillain((_, d)) => d as usize,
-                Location::Environment(_) => 4,
-                Location::Victory => unreachable!(),
-            }] {
-                if n > 0 {
-                    if let Some(id) = self.inner.datapackage_store.id_from_own_location((location, n)) {
-                        location_ids.push(id as i32)
-                    }
-                }
-            }
+    /// Adds a game fetched mid-session. Messages formatted before should be formatted again
+    pub fn add_game(&mut self, game: String, data: &str) {
+        if let Ok(data) = from_str(data) {
+            self.inner.datapackage_store.cache(DataPackageObject { games: [(game, data)].into() });
         }
+    }
 
-        location_ids
+    /// Marks the locations as checked, they're saved once the server reports them as checked in a `RoomUpdate`
+    pub fn get_location_ids(&mut self, locations: Vec<WasmLocation>) -> Vec<i32> {
+        let locations: Vec<Location> = locations.into_iter().map(|l| l.into_inner()).collect();
+        self.inner.send_locations(&locations).into_iter().map(|id| id as i32).collect()
     }
 
     pub fn get_state(&self) -> WasmState {
         wrap_state(&self.inner.state)
     }
 
-    pub fn recieved_items(&mut self, items: Vec<i64>) {
-        for item in items.into_iter().filter_map(|id| self.inner.datapackage_store.id_to_own_item(id)) {
-            self.inner.state.items.set_item(item);
+    /// Returns true if items were skipped and a `Sync` should be sent
+    pub fn recieved_items(&mut self, index: i32, items: Vec<i64>) -> bool {
+        match self.inner.receive_items(index, &items) {
+            ItemSync::Applied => {
+                self.inner.save();
+                false
+            }
+            ItemSync::Gap => true,
+        }
+    }
+
+    pub fn room_update(&mut self, room_update: &str) {
+        if let Ok(room_update) = from_str::<RoomUpdate>(room_update) {
+            if self
+                .inner
+                .update_locations(&room_update.checked_locations.unwrap_or_default(), &room_update.missing_locations.unwrap_or_default())
+            {
+                self.inner.save();
+            }
         }
     }
 
     pub fn exit(&self) {
-        self.inner.persistent_store.save(&self.inner.state.checked_locations);
+        self.inner.save();
     }
 
     pub fn victory(&mut self) {
         self.inner.state.checked_locations.victory = true;
+        self.inner.save();
     }
 }
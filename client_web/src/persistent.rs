@@ -1,59 +1,99 @@
 use base64::{prelude::BASE64_STANDARD, Engine};
-use client_lib::{
-    data::{TeamVillain, Villain},
-    persistent::PersistentStore,
-    state::Locations,
-};
-use web_sys::window;
+use client_lib::persistent::{adopted_name, backup_name, decode, encode, BackupSchedule, PersistentStore, SaveKey, SlotSave, SAVE_BACKUPS};
+use js_sys::Date;
+use std::time::Duration;
+use web_sys::{window, Storage};
 
 pub struct WebPersistentStore {
-    seed: String,
+    key: SaveKey,
+    backups: BackupSchedule,
+    adopt_seed_save: bool,
+}
+
+impl WebPersistentStore {
+    /// Whether a save named after the seed alone is taken over when the slot has none of its own, see
+    /// `DefaultPersistentStore::adopt_seed_save`
+    pub fn adopt_seed_save(mut self, adopt: bool) -> Self {
+        self.adopt_seed_save = adopt;
+        self
+    }
+}
+
+fn local_storage() -> Option<Storage> {
+    window()?.local_storage().ok()?
+}
+
+// localStorage keeps no modification times, so when the newest backup was made is kept next to it, in milliseconds
+fn backup_time_key(name: &str) -> String {
+    format!("{}-time", backup_name(name, 1))
+}
+
+fn backup_age(local_storage: &Storage, name: &str) -> Option<Duration> {
+    let made = local_storage.get_item(&backup_time_key(name)).ok()??.parse::<f64>().ok()?;
+    Some(Duration::from_millis((Date::now() - made).max(0.0) as u64))
+}
+
+// Saves from before they were versioned are base64 encoded binary
+fn decode_item(item: &str) -> Option<SlotSave> {
+    decode(item.as_bytes()).or_else(|| BASE64_STANDARD.decode(item).ok().and_then(|buf| decode(&buf)))
+}
+
+// The save or the newest previous save that's fine
+fn load_item(local_storage: &Storage, name: &str) -> Option<SlotSave> {
+    let mut keys = std::iter::once(name.to_string()).chain((1..=SAVE_BACKUPS).map(|n| backup_name(name, n)));
+    keys.find_map(|key| local_storage.get_item(&key).ok().flatten().and_then(|item| decode_item(&item)))
 }
 
 impl PersistentStore for WebPersistentStore {
-    fn new(seed: &str) -> Self {
-        WebPersistentStore { seed: seed.to_string() }
+    fn new(key: &SaveKey) -> Self {
+        WebPersistentStore {
+            key: key.clone(),
+            backups: BackupSchedule::default(),
+            adopt_seed_save: false,
+        }
     }
 
-    fn load(&self) -> Locations {
-        if let Some(window) = window() {
-            if let Ok(Some(local_storage)) = window.local_storage() {
-                if let Ok(Some(base64)) = local_storage.get_item(&self.seed) {
-                    if let Ok(buf) = BASE64_STANDARD.decode(&base64) {
-                        if buf.len() != 1 + Villain::variant_count() + TeamVillain::variant_count() + 16 + 8 {
-                            return Locations::new();
-                        } else {
-                            let mut locations = Locations::new();
-                            locations.victory = buf[0] > 0;
-                            let mut start = 1;
-                            locations.villains.copy_from_slice(&buf[start..start + Villain::variant_count()]);
-                            start += Villain::variant_count();
-                            locations.team_villains.copy_from_slice(&buf[start..start + TeamVillain::variant_count()]);
-                            start += TeamVillain::variant_count();
-                            locations.variants = u128::from_le_bytes(buf[start..start + 16].try_into().unwrap());
-                            start += 16;
-                            locations.environments = u64::from_le_bytes(buf[start..start + 8].try_into().unwrap());
-                            return locations;
-                        }
-                    }
-                }
-            }
+    fn load(&self) -> SlotSave {
+        let Some(local_storage) = local_storage() else {
+            return SlotSave::default();
+        };
+        if let Some(save) = load_item(&local_storage, &self.key.name()) {
+            return save;
         }
 
-        Locations::new()
-    }
+        if !self.adopt_seed_save {
+            return SlotSave::default();
+        }
 
-    fn save(&self, locations: &Locations) {
-        if let Some(window) = window() {
-            if let Ok(Some(local_storage)) = window.local_storage() {
-                let mut buf = vec![if locations.victory { 1 } else { 0 }];
-                buf.extend(locations.villains);
-                buf.extend(locations.team_villains);
-                buf.extend(locations.variants.to_le_bytes());
-                buf.extend(locations.environments.to_le_bytes());
+        // Saved under the slot's name and the seed-only save renamed, so it's only adopted once
+        let Some(save) = load_item(&local_storage, &self.key.seed) else {
+            return SlotSave::default();
+        };
+        self.save(&save);
+        if let Ok(Some(item)) = local_storage.get_item(&self.key.seed) {
+            if local_storage.set_item(&adopted_name(&self.key.seed), &item).is_ok() {
+                let _ = local_storage.remove_item(&self.key.seed);
+            }
+        }
+        save
+    }
 
-                let _ = local_storage.set_item(&self.seed, &BASE64_STANDARD.encode(&buf));
+    fn save(&self, save: &SlotSave) {
+        if let Some(local_storage) = local_storage() {
+            let name = self.key.name();
+            if let Ok(Some(current)) = local_storage.get_item(&name) {
+                if self.backups.due(backup_age(&local_storage, &name)) {
+                    self.backups.rotate(&name, |from, to| {
+                        if let Ok(Some(item)) = local_storage.get_item(from) {
+                            let _ = local_storage.set_item(to, &item);
+                        }
+                    });
+                    let _ = local_storage.set_item(&backup_name(&name, 1), &current);
+                    let _ = local_storage.set_item(&backup_time_key(&name), &Date::now().to_string());
+                }
             }
+            self.backups.saved();
+            let _ = local_storage.set_item(&name, &String::from_utf8_lossy(&encode(save)));
         }
     }
 }
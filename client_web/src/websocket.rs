@@ -0,0 +1,119 @@
+use archipelago_client::{Client, Error, Frame};
+use archipelago_protocol::RoomInfo;
+use futures_util::{Sink, Stream};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver},
+    oneshot,
+};
+use wasm_bindgen::{closure::Closure, JsCast};
+use web_sys::{CloseEvent, Event, MessageEvent, WebSocket};
+
+/// A browser websocket, to use `archipelago_client::Client` from the page.
+///
+/// Browsers answer the server's pings themselves and don't let pages send any, so pings are dropped and the client
+/// should be used without a keepalive
+pub struct WebSocketTransport {
+    socket: WebSocket,
+    receiver: UnboundedReceiver<Frame>,
+    // The socket only holds on to its handlers as long as they're kept alive here
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+    _on_close: Closure<dyn FnMut(CloseEvent)>,
+}
+
+impl WebSocketTransport {
+    /// Opens a websocket to `url`, e.g. `wss://archipelago.gg:38281`, and waits until it's connected
+    pub async fn open(url: &str) -> Result<WebSocketTransport, Error> {
+        let socket = WebSocket::new(url).map_err(|_| Error::InvalidAddress(url.into()))?;
+        let (sender, receiver) = unbounded_channel();
+
+        let frames = sender.clone();
+        // Archipelago only sends text, anything else is left out
+        let on_message = Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+            if let Some(text) = event.data().as_string() {
+                let _ = frames.send(Frame::Text(text));
+            }
+        });
+        let on_close = Closure::<dyn FnMut(CloseEvent)>::new(move |_: CloseEvent| {
+            let _ = sender.send(Frame::Close);
+        });
+        socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        socket.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+
+        // An error before the socket opened means it never will
+        let (opened, open) = oneshot::channel();
+        let mut opened = Some(opened);
+        let on_open_or_error = Closure::<dyn FnMut(Event)>::new(move |event: Event| {
+            if let Some(opened) = opened.take() {
+                let _ = opened.send(event.type_() == "open");
+            }
+        });
+        socket.set_onopen(Some(on_open_or_error.as_ref().unchecked_ref()));
+        socket.set_onerror(Some(on_open_or_error.as_ref().unchecked_ref()));
+        let is_open = open.await.unwrap_or(false);
+        socket.set_onopen(None);
+        socket.set_onerror(None);
+        if !is_open {
+            return Err(Error::ConnectionClosed);
+        }
+
+        Ok(WebSocketTransport {
+            socket,
+            receiver,
+            _on_message: on_message,
+            _on_close: on_close,
+        })
+    }
+
+    /// Opens a websocket to `url` and waits for the server's `RoomInfo`, see `Client::with_transport`
+    pub async fn connect(url: &str) -> Result<(Client<WebSocketTransport>, RoomInfo), Error> {
+        Client::with_transport(WebSocketTransport::open(url).await?).await
+    }
+}
+
+impl Drop for WebSocketTransport {
+    fn drop(&mut self) {
+        self.socket.set_onmessage(None);
+        self.socket.set_onclose(None);
+        let _ = self.socket.close();
+    }
+}
+
+impl Stream for WebSocketTransport {
+    type Item = Result<Frame, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx).map(|frame| frame.map(Ok))
+    }
+}
+
+impl Sink<Frame> for WebSocketTransport {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match self.socket.ready_state() {
+            WebSocket::OPEN => Poll::Ready(Ok(())),
+            _ => Poll::Ready(Err(Error::ConnectionClosed)),
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, frame: Frame) -> Result<(), Error> {
+        match frame {
+            Frame::Text(text) => self.socket.send_with_str(&text).map_err(|_| Error::ConnectionClosed),
+            Frame::Ping(_) | Frame::Pong(_) => Ok(()),
+            Frame::Close => self.socket.close().map_err(|_| Error::ConnectionClosed),
+        }
+    }
+
+    // The browser sends right away, there's nothing to flush
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(self.socket.close().map_err(|_| Error::ConnectionClosed))
+    }
+}
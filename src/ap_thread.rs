@@ -1,22 +1,40 @@
 use crate::Update;
-use archipelago_client::{self, ClientReceiver};
-use archipelago_protocol::ServerMessage;
-use tokio::{sync::mpsc::UnboundedSender, task::yield_now};
+use archipelago_client::{ConnectionStatus, Event, SupervisedSender};
+use archipelago_protocol::{ServerMessage, SetReply};
+use client_lib::Connection;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 
-pub async fn ap_thread(client_sender: UnboundedSender<Update>, mut ap_receiver: ClientReceiver) {
-    loop {
-        let res = ap_receiver.recv().await;
-        if let Ok(Some(res)) = res {
-            match res {
-                ServerMessage::ReceivedItems(packet) => {
-                    let _ = client_sender.send(Update::Items(packet.items.iter().map(|item| item.item).collect()));
-                }
-                ServerMessage::PrintJSON(msg) => {
-                    let _ = client_sender.send(Update::Msg(msg));
-                }
-                _ => (),
+pub async fn ap_thread(client_sender: UnboundedSender<Update>, mut events: UnboundedReceiver<Event>) {
+    while let Some(event) = events.recv().await {
+        let _ = match event {
+            Event::Message(ServerMessage::Connected(packet)) => client_sender.send(Update::Locations(packet.checked_locations, packet.missing_locations)),
+            Event::Message(ServerMessage::ReceivedItems(packet)) => client_sender.send(Update::Items(packet.index, packet.items.iter().map(|item| item.item).collect())),
+            Event::Message(ServerMessage::RoomUpdate(packet)) if packet.checked_locations.is_some() || packet.missing_locations.is_some() => {
+                client_sender.send(Update::Locations(packet.checked_locations.unwrap_or_default(), packet.missing_locations.unwrap_or_default()))
             }
+            Event::Message(ServerMessage::PrintJSON(msg)) => client_sender.send(Update::Msg(msg)),
+            Event::Message(ServerMessage::DataPackage(packet)) => client_sender.send(Update::DataPackage(packet.data)),
+            Event::Message(_) => Ok(()),
+            Event::Status(ConnectionStatus::Connected) => client_sender.send(Update::Connection(Connection::Connected)),
+            Event::Status(ConnectionStatus::Reconnecting { attempt }) => client_sender.send(Update::Connection(Connection::Reconnecting(attempt))),
+            Event::Status(ConnectionStatus::Refused(_)) => client_sender.send(Update::Connection(Connection::Refused)),
+            Event::Status(ConnectionStatus::Ended) => client_sender.send(Update::Connection(Connection::Ended)),
+        };
+    }
+}
+
+pub async fn shared_save_thread(client_sender: UnboundedSender<Update>, mut updates: UnboundedReceiver<SetReply>) {
+    while let Some(reply) = updates.recv().await {
+        if client_sender.send(Update::SharedSave(reply.value().clone())).is_err() {
+            return;
+        }
+    }
+}
+
+pub async fn retrieve_shared_save(ap_sender: SupervisedSender, client_sender: UnboundedSender<Update>, storage_key: String) {
+    if let Ok(retrieved) = ap_sender.get(vec![storage_key.clone()]).await {
+        if let Some(value) = retrieved.get(&storage_key) {
+            let _ = client_sender.send(Update::SharedSave(value.clone()));
         }
-        yield_now().await;
     }
 }
@@ -1,22 +1,25 @@
 use client_lib::{
     data::{Environment, Hero, Location, TeamVillain, Variant, Villain},
+    datapackage::{DatapackageStore, GAME},
     state::State,
+    Connection,
 };
 use console::{style, StyledObject, Term};
 use num::FromPrimitive;
 use std::{
-    collections::VecDeque,
+    collections::{HashSet, VecDeque},
     io::{stdout, StdoutLock, Write},
 };
 use strum::IntoEnumIterator;
 
 #[allow(clippy::too_many_lines)]
-pub fn print(term: &Term, state: &State, filter: &str, cursor_x: usize, cursor_y: usize, msg_buffer: &VecDeque<String>, multi_send: bool) -> bool {
+#[allow(clippy::too_many_arguments)]
+pub fn print(term: &Term, state: &State, filter: &Filter, cursor_x: usize, cursor_y: usize, msg_buffer: &VecDeque<String>, multi_send: bool, connection: Connection) -> bool {
     let available = state.available_locations();
-    let scroll_y = if cursor_y < 10 { 0 } else { cursor_y - 10 };
+    let scroll_y = cursor_y.saturating_sub(10);
     let _ = term.clear_screen();
     let mut lock = stdout().lock();
-    let _ = writeln!(lock, "{}", if filter.is_empty() { "Type to filter..." } else { filter });
+    let _ = writeln!(lock, "{}", if filter.text.is_empty() { "Type to filter..." } else { &filter.text });
 
     let (rows, cols) = term.size();
     let column_sizes = calc_columns(cols as usize, cursor_x);
@@ -36,7 +39,7 @@ pub fn print(term: &Term, state: &State, filter: &str, cursor_x: usize, cursor_y
     }
 
     if available.victory {
-        if filter.to_lowercase() == "oblivaeon" {
+        if filter.text.to_lowercase() == "oblivaeon" {
             return true;
         }
         let _ = term.move_cursor_to(cols as usize - 18, 1);
@@ -50,10 +53,29 @@ pub fn print(term: &Term, state: &State, filter: &str, cursor_x: usize, cursor_y
         let _ = write!(lock, " {}", style("Multi-send enabled").bright());
     }
 
+    match connection {
+        Connection::Connected => (),
+        Connection::Reconnecting(attempt) => {
+            let status = format!("Reconnecting... (attempt {attempt})");
+            let _ = term.move_cursor_to(cols as usize - status.len() - 1, 0);
+            let _ = write!(lock, " {}", style(status).yellow());
+        }
+        Connection::Refused => {
+            let status = "Reconnect refused by server, restart to connect again";
+            let _ = term.move_cursor_to(cols as usize - status.len() - 1, 0);
+            let _ = write!(lock, " {}", style(status).red());
+        }
+        Connection::Ended => {
+            let status = "Replay finished";
+            let _ = term.move_cursor_to(cols as usize - status.len() - 1, 0);
+            let _ = write!(lock, " {}", style(status).yellow());
+        }
+    }
+
     let mut offset_x = 0;
     if column_sizes[0] > 0 {
         let mut offset = 1;
-        for (v, y) in Villain::iter().filter(|v| state.items.has_villain(*v)).filter(|v| filter_match(filter, v.as_str())).zip(0..) {
+        for (v, y) in Villain::iter().filter(|v| state.items.has_villain(*v)).filter(|v| filter.matches(v.as_str())).zip(0..) {
             if scroll_y == 0 || y > scroll_y {
                 let _ = term.move_cursor_to(offset_x, y + 11 - scroll_y);
                 if cursor_x == 0 && cursor_y == y {
@@ -65,11 +87,7 @@ pub fn print(term: &Term, state: &State, filter: &str, cursor_x: usize, cursor_y
             offset += 1;
         }
 
-        for (v, y) in TeamVillain::iter()
-            .filter(|v| state.items.has_team_villain(*v))
-            .filter(|v| filter_match(filter, v.as_str()))
-            .zip(offset..)
-        {
+        for (v, y) in TeamVillain::iter().filter(|v| state.items.has_team_villain(*v)).filter(|v| filter.matches(v.as_str())).zip(offset..) {
             if scroll_y == 0 || y > scroll_y {
                 let _ = term.move_cursor_to(offset_x, y + 11 - scroll_y);
                 if cursor_x == 0 && cursor_y == y {
@@ -84,7 +102,7 @@ pub fn print(term: &Term, state: &State, filter: &str, cursor_x: usize, cursor_y
     offset_x += column_sizes[0];
 
     if column_sizes[1] > 0 {
-        for (e, y) in Environment::iter().filter(|e| state.items.has_environment(*e)).filter(|e| filter_match(filter, e.as_str())).zip(0..) {
+        for (e, y) in Environment::iter().filter(|e| state.items.has_environment(*e)).filter(|e| filter.matches(e.as_str())).zip(0..) {
             if scroll_y == 0 || y > scroll_y {
                 let _ = term.move_cursor_to(offset_x, y + 11 - scroll_y);
                 if cursor_x == 1 && cursor_y == y {
@@ -108,11 +126,11 @@ pub fn print(term: &Term, state: &State, filter: &str, cursor_x: usize, cursor_y
             .filter_map(|(b, h)| Hero::from_i32(h).map(|hero| (b, hero)))
             .filter(|(b, _)| b.count_ones() > 0)
             .filter(|(b, h)| {
-                filter_match(filter, h.as_str())
+                filter.matches(h.as_str())
                     || (1..7)
                         .filter(move |v| *b & 1 << v > 0)
                         .filter_map(move |v| Variant::from_hero(*h, v))
-                        .any(|v| filter_match(filter, v.as_str()))
+                        .any(|v| filter.matches(v.as_str()))
             })
             .zip(0..)
         {
@@ -153,7 +171,7 @@ pub fn print(term: &Term, state: &State, filter: &str, cursor_x: usize, cursor_y
 
     if column_sizes[3] > 0 {
         let mut offset = 1;
-        for ((v, d), y) in available.villains.iter().filter(|(v, _)| filter_match(filter, v.as_str())).zip(0..) {
+        for ((v, d), y) in available.villains.iter().filter(|(v, _)| filter.matches(v.as_str())).zip(0..) {
             if scroll_y == 0 || y > scroll_y {
                 let _ = term.move_cursor_to(offset_x, y + 11 - scroll_y);
                 if cursor_x == 3 && cursor_y == y {
@@ -165,7 +183,7 @@ pub fn print(term: &Term, state: &State, filter: &str, cursor_x: usize, cursor_y
             offset += 1;
         }
 
-        for ((v, d), y) in available.team_villains.iter().filter(|(v, _)| filter_match(filter, v.as_str())).zip(offset..) {
+        for ((v, d), y) in available.team_villains.iter().filter(|(v, _)| filter.matches(v.as_str())).zip(offset..) {
             if scroll_y == 0 || y > scroll_y {
                 let _ = term.move_cursor_to(offset_x, y + 11 - scroll_y);
                 if cursor_x == 3 && cursor_y == y {
@@ -180,7 +198,7 @@ pub fn print(term: &Term, state: &State, filter: &str, cursor_x: usize, cursor_y
     offset_x += column_sizes[3];
 
     if column_sizes[4] > 0 {
-        for (e, y) in available.environments.iter().filter(|e| filter_match(filter, e.as_str())).zip(0..) {
+        for (e, y) in available.environments.iter().filter(|e| filter.matches(e.as_str())).zip(0..) {
             if scroll_y == 0 || y > scroll_y {
                 let _ = term.move_cursor_to(offset_x, y + 11 - scroll_y);
                 if cursor_x == 4 && cursor_y == y {
@@ -195,7 +213,7 @@ pub fn print(term: &Term, state: &State, filter: &str, cursor_x: usize, cursor_y
     offset_x += column_sizes[4];
 
     if column_sizes[5] > 0 {
-        for (v, y) in available.variants.iter().filter(|v| filter_match(filter, v.as_str())).zip(0..) {
+        for (v, y) in available.variants.iter().filter(|v| filter.matches(v.as_str())).zip(0..) {
             if scroll_y == 0 || y > scroll_y {
                 let _ = term.move_cursor_to(offset_x, y + 11 - scroll_y);
                 if cursor_x == 5 && cursor_y == y {
@@ -216,29 +234,24 @@ pub fn print(term: &Term, state: &State, filter: &str, cursor_x: usize, cursor_y
     false
 }
 
-pub fn find_location(state: &State, filter: &str, cursor_x: usize, cursor_y: usize) -> Option<Location> {
+pub fn find_location(state: &State, filter: &Filter, cursor_x: usize, cursor_y: usize) -> Option<Location> {
     let available = state.available_locations();
     match cursor_x {
         3 => {
-            let villain_count = available.villains.iter().filter(|(v, _)| filter_match(filter, v.as_str())).count();
+            let villain_count = available.villains.iter().filter(|(v, _)| filter.matches(v.as_str())).count();
             if cursor_y < villain_count {
-                available.villains.iter().filter(|(v, _)| filter_match(filter, v.as_str())).nth(cursor_y).map(|v| Location::Villain(*v))
+                available.villains.iter().filter(|(v, _)| filter.matches(v.as_str())).nth(cursor_y).map(|v| Location::Villain(*v))
             } else {
                 available
                     .team_villains
                     .iter()
-                    .filter(|(v, _)| filter_match(filter, v.as_str()))
+                    .filter(|(v, _)| filter.matches(v.as_str()))
                     .nth(cursor_y - villain_count - 1)
                     .map(|v| Location::TeamVillain(*v))
             }
         }
-        4 => available
-            .environments
-            .iter()
-            .filter(|e| filter_match(filter, e.as_str()))
-            .nth(cursor_y)
-            .map(|e| Location::Environment(*e)),
-        5 => available.variants.iter().filter(|v| filter_match(filter, v.as_str())).nth(cursor_y).map(|v| Location::Variant(*v)),
+        4 => available.environments.iter().filter(|e| filter.matches(e.as_str())).nth(cursor_y).map(|e| Location::Environment(*e)),
+        5 => available.variants.iter().filter(|v| filter.matches(v.as_str())).nth(cursor_y).map(|v| Location::Variant(*v)),
         _ => None,
     }
 }
@@ -261,6 +274,40 @@ fn to_dif(d: u8) -> StyledObject<&'static str> {
     }
 }
 
+/// What's typed to filter by. Typing the name of an item or location group of the game filters for everything in it
+pub struct Filter {
+    pub text: String,
+    group: Option<HashSet<String>>,
+}
+
+impl Filter {
+    pub fn new<D>(text: String, datapackage_store: &D) -> Self
+    where
+        D: DatapackageStore,
+    {
+        let groups = [datapackage_store.item_groups(GAME), datapackage_store.location_groups(GAME)];
+        let names: HashSet<String> = groups
+            .into_iter()
+            .flatten()
+            .flat_map(|groups| groups.iter().filter(|(group, _)| group.eq_ignore_ascii_case(&text)))
+            .flat_map(|(_, names)| names.iter().cloned())
+            .collect();
+
+        Filter {
+            group: (!names.is_empty()).then_some(names),
+            text,
+        }
+    }
+
+    // Location names are the name of what's shown followed by " - " and the difficulty or the like
+    fn matches(&self, item: &str) -> bool {
+        match &self.group {
+            Some(names) => names.iter().any(|name| name == item || name.strip_prefix(item).is_some_and(|rest| rest.starts_with(" - "))),
+            None => filter_match(&self.text, item),
+        }
+    }
+}
+
 fn filter_match(filter: &str, item: &str) -> bool {
     let lowercase = filter.to_ascii_lowercase();
     let mut filter_iter = lowercase.chars().peekable();
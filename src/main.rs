@@ -4,23 +4,27 @@ mod format_json;
 mod input_thread;
 
 use anyhow::Result;
-use ap_thread::ap_thread;
-use archipelago_client::Client;
-use archipelago_protocol::ClientStatus;
+use ap_thread::{ap_thread, retrieve_shared_save, shared_save_thread};
+use archipelago_client::{supervise, Backoff, Client, ConnectInfo, ConnectOptions, Proxy, DEFAULT_PORT};
+use archipelago_protocol::{ClientMessage, ClientStatus, GetDataPackage, LocationChecks, RefusalReason, StatusUpdate};
 use clap::Parser;
-use cli::{find_location, print};
+use cli::{find_location, print, Filter};
 use client_lib::{
     data::Location,
-    datapackage::{DatapackageStore, DefaultDatapackageStore},
-    persistent::{DefaultPersistentStore, PersistentStore},
-    DisplayUpdate, Session, Update,
+    data_dir::{DataDir, DEFAULT_PROFILE},
+    datapackage::{DatapackageStore, DefaultDatapackageStore, FilesystemBackend, GAME},
+    persistent::{sole_slot_of_game, DataStoragePersistentStore, DefaultPersistentStore, SaveKey},
+    Connection, DisplayUpdate, ItemSync, Session, Update, MESSAGE_LOG_LEN,
 };
 use console::Term;
 use format_json::format;
 use input_thread::{input_thread, Input};
+use simplelog::{LevelFilter, WriteLogger};
 use std::{
     collections::VecDeque,
+    fs,
     io::{self, BufRead, Write},
+    path::PathBuf,
     process::exit,
 };
 use tokio::{
@@ -44,6 +48,40 @@ struct Args {
     #[allow(clippy::option_option)]
     #[arg(short = 'P', long)]
     password: Option<Option<String>>,
+    /// PEM certificate to trust, for servers with a self-signed certificate
+    #[arg(long)]
+    ca_cert: Option<PathBuf>,
+    /// Don't verify the server's certificate
+    #[arg(long)]
+    accept_invalid_certs: bool,
+    /// Proxy to connect through, like socks5://localhost:1080 or http://localhost:8080
+    #[arg(long)]
+    proxy: Option<String>,
+    /// Write every frame sent and received to this file, to attach to bug reports
+    #[arg(long)]
+    record: Option<PathBuf>,
+    /// Play back a file written with --record instead of connecting to a server. Replays are saved apart from real sessions
+    #[arg(long, conflicts_with_all = ["server", "port", "password", "proxy"])]
+    replay: Option<PathBuf>,
+    /// Keep saves and the datapackage cache here instead of the platform data dir. Can also be set with SOTM_AP_DATA_DIR
+    #[arg(long)]
+    data_dir: Option<PathBuf>,
+    /// Keep saves separate from other profiles' on the same machine. Can also be set with SOTM_AP_PROFILE
+    #[arg(long)]
+    profile: Option<String>,
+    /// Share the save with other devices playing the slot, through the server's data storage
+    #[arg(long)]
+    sync: bool,
+}
+
+/// Only saves locally unless --sync is given
+type SaveStore = DataStoragePersistentStore<DefaultPersistentStore>;
+
+/// The terminal belongs to the UI, so log messages go to a file that can be attached to bug reports
+fn init_log(data_dir: &DataDir) -> io::Result<()> {
+    fs::create_dir_all(data_dir.root())?;
+    let file = fs::File::create(data_dir.log())?;
+    WriteLogger::init(LevelFilter::Info, simplelog::Config::default(), file).map_err(io::Error::other)
 }
 
 fn main() {
@@ -51,37 +89,101 @@ fn main() {
     let (input_sender, mut input_receiver) = unbounded_channel();
     let (server_sender, mut server_receiver) = unbounded_channel();
 
-    let runtime = Builder::new_multi_thread().enable_io().build().unwrap();
-    let (server, port, slot, pass) = get_server_info();
+    let args = Args::parse();
+    let data_dir = match DataDir::new(args.data_dir.clone(), args.profile.clone()) {
+        Ok(data_dir) => data_dir,
+        Err(err) => {
+            println!("{err}");
+            exit(1);
+        }
+    };
+    if let Err(err) = init_log(&data_dir) {
+        println!("Could not open {}, continuing without a log: {err}", data_dir.log().display());
+    }
+
+    let runtime = Builder::new_multi_thread().enable_io().enable_time().build().unwrap();
+    let sync = args.sync;
+    let (options, mut slot, mut pass) = get_server_info(args);
+
+    let (client, session) = loop {
+        let err = match runtime.block_on(connect(&options, &data_dir, &slot, pass.as_deref(), sync)) {
+            Ok(connection) => break connection,
+            Err(err) => err,
+        };
 
-    let (client, session) = runtime.block_on(connect(&server, port, &slot, pass.as_deref(), true)).unwrap_or_else(|_| {
-        runtime.block_on(connect(&server, port, &slot, pass.as_deref(), false)).unwrap_or_else(|err| {
-            println!("Failed to connect to archipelago server");
-            dbg!(err);
+        let Some(reasons) = refusal_reasons(&err) else {
+            println!("Failed to connect to archipelago server: {err}");
             exit(1);
-        })
-    });
+        };
+
+        // The server can refuse a slot for more than one reason, each slot or password is only asked for once
+        let mut ask_slot = false;
+        let mut ask_pass = false;
+        for reason in reasons {
+            match reason {
+                RefusalReason::InvalidSlot => {
+                    println!("There is no slot named {slot} in this room");
+                    ask_slot = true;
+                }
+                RefusalReason::InvalidPassword => {
+                    println!("Wrong password");
+                    ask_pass = true;
+                }
+                RefusalReason::InvalidGame => {
+                    println!("Slot {slot} is not playing {GAME}");
+                    ask_slot = true;
+                }
+                RefusalReason::IncompatibleVersion => {
+                    println!("This client is not compatible with the server's version of archipelago");
+                    exit(1);
+                }
+                RefusalReason::InvalidItemsHandling | RefusalReason::Other(_) => {
+                    println!("Connection refused by server: {reason:?}");
+                    exit(1);
+                }
+            }
+        }
+        if ask_slot {
+            slot = prompt_slot();
+        }
+        if ask_pass {
+            pass = prompt("Password?");
+        }
+    };
+
+    let connect_info = ConnectInfo {
+        options: options.with_address(client.url()),
+        game: GAME.into(),
+        name: slot.clone(),
+        uuid: String::new(),
+        password: pass,
+        items_handling: Some(7),
+        tags: vec!["AP".into()],
+    };
 
     println!("Connected!");
 
-    let datapackage_store = session.datapackage_store.clone();
+    let mut datapackage_store = session.datapackage_store.clone();
     let slot = session.slot.clone();
     let players = session.players.clone();
     let mut state = session.state;
-    let mut filter = String::new();
+    let mut filter = Filter::new(String::new(), &datapackage_store);
     let mut cursor_x = 0;
     let mut cursor_y = 0;
-    let mut msg_buffer = VecDeque::with_capacity(10);
-    let mut multi_send = false;
+    // Messages from the last session are shown until new ones push them out
+    let mut messages = session.messages.clone();
+    let mut msg_buffer: VecDeque<_> = messages.iter().map(|msg| format(&datapackage_store, msg.clone(), &players, &slot)).collect();
+    let mut multi_send = session.multi_send;
+    let mut connection = Connection::Connected;
 
     let term = Term::stdout();
     let _ = term.hide_cursor();
 
-    runtime.spawn(run(session, client, ap_receiver, server_sender));
+    runtime.spawn(run(session, client, connect_info, ap_receiver, server_sender, sync));
     runtime.spawn(input_thread(input_sender));
     runtime.block_on(async move {
         loop {
-            let send_victory = print(&term, &state, &filter, cursor_x, cursor_y, &msg_buffer, multi_send);
+            let send_victory = print(&term, &state, &filter, cursor_x, cursor_y, &msg_buffer, multi_send, connection);
             if send_victory {
                 let _ = ap_sender.send(Update::Send(vec![Location::Victory]));
             }
@@ -89,14 +191,24 @@ fn main() {
             select! {
                 Some(update) = server_receiver.recv() => match update {
                     DisplayUpdate::Msg(msg) => {
-                        if msg_buffer.len() > 9 {
+                        if msg_buffer.len() >= MESSAGE_LOG_LEN {
+                            messages.pop_front();
                             msg_buffer.pop_front();
                         }
-                        msg_buffer.push_back(format(&datapackage_store, msg, &players, &slot));
+                        msg_buffer.push_back(format(&datapackage_store, msg.clone(), &players, &slot));
+                        messages.push_back(msg);
+                    }
+                    DisplayUpdate::Datapackage(new_datapackage_store) => {
+                        datapackage_store = new_datapackage_store;
+                        filter = Filter::new(filter.text, &datapackage_store);
+                        msg_buffer = messages.iter().map(|msg| format(&datapackage_store, msg.clone(), &players, &slot)).collect();
                     }
                     DisplayUpdate::State(new_state) => {
                         state = new_state;
                     }
+                    DisplayUpdate::Connection(new_connection) => {
+                        connection = new_connection;
+                    }
                     DisplayUpdate::Exit => {
                         let _ = term.show_cursor();
                         let _ = term.clear_screen();
@@ -104,7 +216,7 @@ fn main() {
                     }
                 },
                 Some(input) = input_receiver.recv() => match input {
-                    Input::Filter(new_filter) => filter = new_filter,
+                    Input::Filter(new_filter) => filter = Filter::new(new_filter, &datapackage_store),
                     Input::CursorLeft => cursor_x = cursor_x.saturating_sub(1),
                     Input::CursorRight => {
                         if cursor_x < 5 {
@@ -114,7 +226,10 @@ fn main() {
                     Input::CursorUp => cursor_y = cursor_y.saturating_sub(1),
                     Input::CursorDown => cursor_y += 1,
                     Input::CursorHome => (cursor_x, cursor_y) = (0, 0),
-                    Input::Select => multi_send = !multi_send,
+                    Input::Select => {
+                        multi_send = !multi_send;
+                        let _ = ap_sender.send(Update::MultiSend(multi_send));
+                    }
                     Input::Send => {
                         if let Some(location) = find_location(&state, &filter, cursor_x, cursor_y) {
                             let locations = if multi_send { resolve_multi_send(location) } else { vec![location] };
@@ -145,24 +260,28 @@ fn main() {
     });
 }
 
-fn get_server_info() -> (String, u16, String, Option<String>) {
-    let args = Args::parse();
+fn get_server_info(args: Args) -> (ConnectOptions, String, Option<String>) {
+    if let Some(replay) = args.replay {
+        let slot = if let Some(slot) = args.slot { slot.unwrap_or("Player".into()) } else { prompt_slot() };
+        let options = ConnectOptions::new(replay.display().to_string()).record(args.record).replay(Some(replay));
+        return (options, if slot.is_empty() { String::from("Player") } else { slot }, None);
+    }
 
     let mut server = if let Some(server) = args.server {
         server.unwrap_or("archipelago.gg".into())
     } else {
         prompt("Server? (default: archipelago.gg)").unwrap_or("archipelago.gg".into())
     };
+    // A full url already has everything needed to reach the server
+    let has_scheme = server.contains("://");
     let mut port = if let Some(port) = args.port {
         port.unwrap_or("38281".into())
+    } else if has_scheme {
+        String::new()
     } else {
         prompt("Port? (default: 38281)").unwrap_or("38281".into())
     };
-    let mut slot = if let Some(slot) = args.slot {
-        slot.unwrap_or("Player".into())
-    } else {
-        prompt("Slot? (default: Player)").unwrap_or("Player".into())
-    };
+    let mut slot = if let Some(slot) = args.slot { slot.unwrap_or("Player".into()) } else { prompt_slot() };
     let password = if let Some(password) = args.password { password } else { prompt("Password?") };
 
     if server.is_empty() {
@@ -177,7 +296,34 @@ fn get_server_info() -> (String, u16, String, Option<String>) {
         slot = String::from("Player");
     }
 
-    (server, port.parse().unwrap_or(38281), slot, password)
+    let address = if has_scheme { server } else { format!("{server}:{}", port.parse().unwrap_or(DEFAULT_PORT)) };
+    let mut options = ConnectOptions::new(address).danger_accept_invalid_certs(args.accept_invalid_certs).record(args.record);
+
+    if let Some(path) = args.ca_cert {
+        match fs::read(&path) {
+            Ok(pem) => options = options.add_root_certificate(pem),
+            Err(err) => {
+                println!("Failed to read certificate {}: {err}", path.display());
+                exit(1);
+            }
+        }
+    }
+
+    if let Some(proxy) = args.proxy {
+        match Proxy::from_url(&proxy) {
+            Ok(proxy) => options = options.proxy(Some(proxy)),
+            Err(err) => {
+                println!("{err}");
+                exit(1);
+            }
+        }
+    }
+
+    (options, slot, password)
+}
+
+fn prompt_slot() -> String {
+    prompt("Slot? (default: Player)").unwrap_or("Player".into())
 }
 
 fn prompt(text: &str) -> Option<String> {
@@ -203,18 +349,28 @@ fn resolve_multi_send(location: Location) -> Vec<Location> {
     }
 }
 
-async fn connect(server: &str, port: u16, slot: &str, pass: Option<&str>, secure: bool) -> Result<(Client, Session<DefaultDatapackageStore, DefaultPersistentStore>)> {
-    let (mut client, room_info) = Client::new(&format!("{}://{server}:{port}", if secure { "wss" } else { "ws" })).await?;
+fn refusal_reasons(err: &anyhow::Error) -> Option<&[RefusalReason]> {
+    match err.downcast_ref::<archipelago_client::Error>() {
+        Some(archipelago_client::Error::ConnectionRefused(reasons)) => Some(reasons),
+        _ => None,
+    }
+}
+
+async fn connect(options: &ConnectOptions, data_dir: &DataDir, slot: &str, pass: Option<&str>, sync: bool) -> Result<(Client, Session<DefaultDatapackageStore, SaveStore>)> {
+    let (mut client, room_info) = Client::connect_with(options).await?;
 
-    let mut datapackage_store = DefaultDatapackageStore::new(room_info.datapackage_checksums);
+    let mut datapackage_store = DefaultDatapackageStore::with_backend(FilesystemBackend::new(data_dir.datapackage()), room_info.datapackage_checksums);
     let missing_games = datapackage_store.missing_games();
+    for game in &missing_games {
+        println!("Missing datapackage for {game}...");
+    }
     let data_package = if !missing_games.is_empty() {
         Some(client.get_data_package(Some(missing_games)).await?.data)
     } else {
         None
     };
 
-    let connected = client.connect("Sentinels of the Multiverse", slot, "", pass, Some(7), &["AP".into()]).await?;
+    let connected = client.connect(GAME, slot, "", pass, Some(7), &["AP".into()]).await?;
 
     if let Some(data) = data_package {
         datapackage_store.cache(data);
@@ -222,21 +378,66 @@ async fn connect(server: &str, port: u16, slot: &str, pass: Option<&str>, secure
 
     datapackage_store.build_player_map(&connected);
 
-    client.sync().await?;
+    let items = client.sync().await?;
 
-    Ok((client, Session::new(&room_info.seed_name, datapackage_store, connected, slot)))
+    let key = SaveKey {
+        seed: room_info.seed_name.clone(),
+        team: connected.team,
+        slot: connected.slot,
+    };
+    let local_store = if options.is_replay() {
+        match fs::remove_dir_all(data_dir.replay()) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+            _ => DefaultPersistentStore::in_dir(data_dir.replay(), &key),
+        }
+    } else if data_dir.profile() == DEFAULT_PROFILE {
+        // Saves used to be kept in the working directory
+        DefaultPersistentStore::in_dir(data_dir.persistent(), &key)
+            .with_fallback("./persistent")
+            .adopt_seed_save(sole_slot_of_game(&connected.slot_info))
+    } else {
+        DefaultPersistentStore::in_dir(data_dir.persistent(), &key).adopt_seed_save(sole_slot_of_game(&connected.slot_info))
+    };
+
+    let mut persistent_store = DataStoragePersistentStore::with_local(local_store, &key);
+    if sync {
+        let storage_key = persistent_store.storage_key().to_string();
+        let retrieved = client.get(vec![storage_key.clone()]).await?;
+        if let Some(value) = retrieved.get(&storage_key) {
+            persistent_store.receive(value);
+        }
+    }
+
+    let mut session = Session::with_stores(datapackage_store, persistent_store, connected, slot);
+    session.receive_items(items.index, &items.items.iter().map(|item| item.item).collect::<Vec<_>>());
+
+    Ok((client, session))
 }
 
 async fn run(
-    mut session: Session<DefaultDatapackageStore, DefaultPersistentStore>,
+    mut session: Session<DefaultDatapackageStore, SaveStore>,
     client: Client,
+    connect_info: ConnectInfo,
     mut locations_to_send: UnboundedReceiver<Update>,
-    display_sender: UnboundedSender<DisplayUpdate>,
+    display_sender: UnboundedSender<DisplayUpdate<DefaultDatapackageStore>>,
+    sync: bool,
 ) -> ! {
-    let (mut ap_sender, ap_receiver) = client.split();
+    let (ap_sender, events) = supervise(client, connect_info, Backoff::default());
     let (sender, mut receiver) = unbounded_channel::<Update>();
 
-    spawn(ap_thread(sender, ap_receiver));
+    if sync {
+        let set_sender = ap_sender.clone();
+        session.persistent_store.send_to(move |set| {
+            let _ = set_sender.send(ClientMessage::Set(set));
+        });
+        if let Ok(updates) = ap_sender.subscribe(vec![session.persistent_store.storage_key().to_string()]) {
+            spawn(shared_save_thread(sender.clone(), updates));
+        }
+        // Shares whatever this device has that the stored save doesn't
+        session.save();
+    }
+
+    spawn(ap_thread(sender.clone(), events));
 
     loop {
         if let Some(update) = select! {
@@ -244,51 +445,78 @@ async fn run(
             v = receiver.recv() => v
         } {
             let _ = match update {
-                Update::Msg(msg) => display_sender.send(DisplayUpdate::Msg(msg)),
-                Update::Items(item_ids) => {
-                    for id in item_ids {
-                        if let Some(item) = session.datapackage_store.id_to_own_item(id) {
-                            session.state.items.set_item(item);
+                Update::Msg(msg) => {
+                    let misses = session.datapackage_store.report_misses(&msg);
+                    if !misses.is_empty() {
+                        let _ = ap_sender.send(ClientMessage::GetDataPackage(GetDataPackage { games: Some(misses.into()) }));
+                    }
+                    session.log_message(msg.clone());
+                    display_sender.send(DisplayUpdate::Msg(msg))
+                }
+                Update::DataPackage(data) => {
+                    session.datapackage_store.cache(data);
+                    display_sender.send(DisplayUpdate::Datapackage(session.datapackage_store.clone()))
+                }
+                Update::Items(index, item_ids) => {
+                    match session.receive_items(index, &item_ids) {
+                        ItemSync::Applied => session.save(),
+                        ItemSync::Gap => {
+                            let _ = ap_sender.send(ClientMessage::Sync);
                         }
                     }
                     display_sender.send(DisplayUpdate::State(session.state))
                 }
+                Update::Locations(checked, missing) => {
+                    if session.update_locations(&checked, &missing) {
+                        session.save();
+                    }
+                    display_sender.send(DisplayUpdate::State(session.state))
+                }
+                Update::Connection(connection) => {
+                    // Changes to the shared save made while reconnecting were never notified
+                    if sync && connection == Connection::Connected {
+                        let storage_key = session.persistent_store.storage_key().to_string();
+                        spawn(retrieve_shared_save(ap_sender.clone(), sender.clone(), storage_key));
+                    }
+                    display_sender.send(DisplayUpdate::Connection(connection))
+                }
                 Update::Send(locations) => {
-                    let mut location_ids = vec![];
+                    // Shown as checked right away, but only saved once the server confirms them, see `Session::send_locations`
+                    let location_ids = session.send_locations(&locations);
+                    let victory = locations.contains(&Location::Victory);
+                    let mut batch = vec![];
+                    if victory {
+                        batch.push(ClientMessage::StatusUpdate(StatusUpdate { status: ClientStatus::Goal }));
+                    }
+                    if !location_ids.is_empty() {
+                        batch.push(ClientMessage::LocationChecks(LocationChecks { locations: location_ids }));
+                    }
 
-                    for location in locations.iter() {
-                        if *location == Location::Victory {
-                            let res = ap_sender.status_update(ClientStatus::Goal).await;
-                            if res.is_ok() {
-                                session.state.checked_locations.victory = true;
-                            }
-                        } else {
-                            for n in 0..=session.state.slot_data.locations_per[match location {
-                                Location::Variant(_) => 5,
-                                Location::Villain((_, d)) | Location::TeamVillain((_, d)) => *d as usize,
-                                Location::Environment(_) => 4,
-                                Location::Victory => unreachable!(),
-                            }] {
-                                if n > 0 {
-                                    if let Some(id) = session.datapackage_store.id_from_own_location((*location, n)) {
-                                        location_ids.push(id)
-                                    }
-                                }
-                            }
-                        }
+                    // The server never confirms the goal, so it's saved once it's on its way
+                    if ap_sender.send_batch(batch).is_ok() && victory {
+                        session.state.checked_locations.victory = true;
+                        session.save();
                     }
 
-                    let res = ap_sender.location_checks(location_ids).await;
-                    if res.is_ok() {
-                        for location in locations {
-                            session.state.checked_locations.mark_location(location);
+                    display_sender.send(DisplayUpdate::State(session.state))
+                }
+                Update::SharedSave(value) => {
+                    if let Some(save) = session.persistent_store.receive(&value) {
+                        let merge = session.merge_locations(&save.locations);
+                        if merge.ahead {
+                            session.save();
+                        } else if merge.gained {
+                            session.persistent_store.save_local(&session.to_save());
                         }
                     }
-
                     display_sender.send(DisplayUpdate::State(session.state))
                 }
+                Update::MultiSend(multi_send) => {
+                    session.multi_send = multi_send;
+                    Ok(())
+                }
                 Update::Exit => {
-                    session.persistent_store.save(&session.state.checked_locations);
+                    session.save();
                     display_sender.send(DisplayUpdate::Exit)
                 }
             };